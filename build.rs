@@ -1,12 +1,35 @@
+extern crate crypto;
 extern crate walkdir;
+extern crate yaml_rust;
 
 use walkdir::WalkDir;
+use yaml_rust::{Yaml, YamlLoader};
+use std::collections::BTreeMap;
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 use std::ffi::OsStr;
 
+#[path = "src/naming.rs"]
+mod naming;
+#[path = "src/schema.rs"]
+#[allow(dead_code)]
+mod schema;
+
+use schema::{PropertyType, Resolver, SchemaNode};
+
+
+/// Property names that have to be renamed to be used as fields: the
+/// strict and reserved keywords of every edition
+static KEYWORDS: [&'static str; 55] = [
+    "abstract", "alignof", "as", "async", "await", "become", "box", "break", "const", "continue",
+    "crate", "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if",
+    "impl", "in", "let", "loop", "macro", "match", "mod", "move", "mut", "offsetof", "override",
+    "priv", "proc", "pub", "pure", "ref", "return", "self", "sizeof", "static", "struct", "super",
+    "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use", "virtual", "where", "while",
+];
+
 
 // Get absolute path to the "target" directory ("build" dir)
 fn get_target_dir() -> PathBuf {
@@ -24,30 +47,272 @@ fn get_top_dir<'a>(target_dir: &'a Path) -> &'a Path {
 }
 
 
+// Write a static array of the schemas to include
+fn write_schemas(f: &mut File, name: &str, schemas: &Vec<String>) {
+    f.write_all(&*format!("pub static {}: [&'static str; {}] = [\n", name, schemas.len())
+                .as_bytes()).unwrap();
+
+    for schema in schemas {
+        f.write_all(schema.as_bytes()).unwrap();
+    }
+    f.write_all(b"];\n").unwrap();
+}
+
+
+// The Rust type values of a property type are converted to, and
+// whether they can be null
+fn rust_type(prop_type: &PropertyType) -> (String, bool) {
+    let rust_type = match *prop_type {
+        PropertyType::Boolean => "bool".to_string(),
+        PropertyType::Integer | PropertyType::Decimal => "f64".to_string(),
+        PropertyType::String | PropertyType::Datetime | PropertyType::Enum(_) => "String".to_string(),
+        PropertyType::Object => "::types::Doc".to_string(),
+        PropertyType::Array(ref inner) => format!("Vec<{}>", rust_type(inner).0),
+        PropertyType::Nullable(ref inner) => return (rust_type(inner).0, true),
+        PropertyType::Union(ref types) => {
+            let mut distinct: Vec<String> = Vec::new();
+            for rust_type in types.iter().map(|prop_type| rust_type(prop_type).0) {
+                if !distinct.contains(&rust_type) {
+                    distinct.push(rust_type)
+                }
+            }
+            match distinct.len() {
+                1 => distinct.remove(0),
+                // Mixed values are left as JSON
+                _ => "::serde_json::Value".to_string(),
+            }
+        },
+    };
+    (rust_type, false)
+}
+
+
+// e.g. aligned_reads_index -> AlignedReadsIndex
+fn struct_name(label: &str) -> String {
+    label.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().map_or(String::new(), |first| first.to_uppercase().chain(chars).collect())
+        })
+        .collect()
+}
+
+
+// e.g. RIN -> rin, type -> type_
+fn field_name(key: &str) -> String {
+    let name = key.to_lowercase();
+    match KEYWORDS.contains(&&*name) {
+        true => format!("{}_", name),
+        false => name,
+    }
+}
+
+
+// The names of the links in a schema, which aren't properties
+fn link_names(schema: &SchemaNode) -> Vec<String> {
+    schema.links().iter().filter_map(|&(link, _)| link.get_kv("name")).collect()
+}
+
+
+// The schemas of the node types, keyed by label
+fn node_schemas(resolver: &Resolver) -> BTreeMap<String, SchemaNode> {
+    resolver.schemas().iter()
+        .filter(|&(id, _)| !id.starts_with("_"))
+        .map(|(id, doc)| {
+            let schema = resolver.resolve(id, doc).unwrap_or_else(|err| panic!("{}: {}", id, err));
+            let label = schema.get_kv("id").expect("schema without id");
+            (label, schema)
+        })
+        .collect()
+}
+
+
+// Check a table name is one postgres will keep as is
+fn check_tablename(tablename: &str) {
+    assert!(tablename.len() <= 63, "table name {} is longer than postgres allows", tablename);
+    assert!(tablename.chars().all(|c| c.is_alphanumeric() || c == '_'),
+            "table name {} isn't a plain identifier", tablename);
+}
+
+
+// Write the node and edge table names, applying the overrides and
+// refusing to build if two node types or links share a table
+fn write_table_names(f: &mut File, schemas: &BTreeMap<String, SchemaNode>, overrides: &Yaml) {
+    let mut node_tables = BTreeMap::new();
+    let mut edge_tables = BTreeMap::new();
+
+    for (label, schema) in schemas {
+        let tablename = naming::node_table(overrides, &*label);
+        node_tables.insert(label.clone(), tablename);
+
+        for (link, _) in schema.links() {
+            let name = link.get_kv("name").expect("link without name");
+            let edge_label = link.get_kv("label").expect("link without label");
+            let dst_label = link.get_kv("target_type").expect("link without target_type");
+            let tablename = naming::edge_table(overrides, &*label, &*edge_label, &*dst_label);
+            edge_tables.insert((label.clone(), edge_label, dst_label), (name, tablename));
+        }
+    }
+
+    let mut owners = BTreeMap::new();
+    for (label, tablename) in &node_tables {
+        check_tablename(tablename);
+        if let Some(other) = owners.insert(tablename.clone(), label.clone()) {
+            panic!("node types {} and {} both use table {}", other, label, tablename);
+        }
+    }
+    for (&(ref src_label, ref edge_label, ref dst_label), &(_, ref tablename)) in &edge_tables {
+        check_tablename(tablename);
+        let owner = format!("{} -{}-> {}", src_label, edge_label, dst_label);
+        if let Some(other) = owners.insert(tablename.clone(), owner.clone()) {
+            panic!("{} and {} both use table {}, add an override to table_names.yaml",
+                   other, owner, tablename);
+        }
+    }
+
+    for (label, tablename) in &node_tables {
+        f.write_all(format!("pub const NODE_{}: &'static str = \"{}\";\n",
+                            label.to_uppercase(), tablename).as_bytes()).unwrap();
+    }
+    f.write_all(b"\n").unwrap();
+    for (&(ref src_label, _, _), &(ref name, ref tablename)) in &edge_tables {
+        f.write_all(format!("pub const EDGE_{}_{}: &'static str = \"{}\";\n",
+                            src_label.to_uppercase(), name.to_uppercase(), tablename).as_bytes()).unwrap();
+    }
+
+    f.write_all(format!("\n/// Node table names keyed by label\n\
+                         pub static NODE_TABLES: [(&'static str, &'static str); {}] = [\n",
+                        node_tables.len()).as_bytes()).unwrap();
+    for (label, tablename) in &node_tables {
+        f.write_all(format!("    (\"{}\", \"{}\"),\n", label, tablename).as_bytes()).unwrap();
+    }
+    f.write_all(format!("];\n\n/// Edge table names keyed by source label, edge label and destination label\n\
+                         pub static EDGE_TABLES: [(&'static str, &'static str, &'static str, &'static str); {}] = [\n",
+                        edge_tables.len()).as_bytes()).unwrap();
+    for (&(ref src_label, ref edge_label, ref dst_label), &(_, ref tablename)) in &edge_tables {
+        f.write_all(format!("    (\"{}\", \"{}\", \"{}\", \"{}\"),\n",
+                            src_label, edge_label, dst_label, tablename).as_bytes()).unwrap();
+    }
+    f.write_all(b"];\n").unwrap();
+}
+
+
+// Write a struct for each node type with a conversion from nodes
+fn write_models(f: &mut File, schemas: &BTreeMap<String, SchemaNode>) {
+    f.write_all(b"use ::conversion::{check_label, optional, required};\n\
+                  use ::errors::EBResult;\n\
+                  use ::types::Node;\n\n").unwrap();
+
+    let mut labels = Vec::new();
+    for (label, schema) in schemas {
+        let name = struct_name(&*label);
+        let links = link_names(schema);
+        let required = schema.get_list("required");
+
+        let mut fields = String::new();
+        let mut conversions = String::new();
+        // Keyed by name, keys next to a $ref replacing those it inlines
+        let properties = schema.get("properties").expect("schema without properties").children.iter()
+            .map(|property| (&*property.key, property))
+            .collect::<BTreeMap<_, _>>();
+        for (key, property) in properties {
+            // The id is the node's, not a property
+            if key == "id" || links.iter().any(|link| link == key) {
+                continue
+            }
+
+            let prop_type = property.property_type().unwrap_or_else(|err| panic!("{}.{}: {}", label, key, err));
+            let (rust_type, nullable) = rust_type(&prop_type);
+            let is_required = !nullable && required.iter().any(|r| r == key);
+            let (field_type, convert) = match is_required {
+                true => (rust_type, "required"),
+                false => (format!("Option<{}>", rust_type), "optional"),
+            };
+            fields.push_str(&*format!("    pub {}: {},\n", field_name(key), field_type));
+            conversions.push_str(&*format!("            {}: try!({}(node, \"{}\")),\n",
+                                           field_name(key), convert, key));
+        }
+
+        f.write_all(format!(
+            "/// Nodes labeled `{label}`\n\
+             #[derive(Debug,Clone,PartialEq)]\n\
+             pub struct {name} {{\n    pub id: String,\n{fields}}}\n\n\
+             impl {name} {{\n    \
+                 pub fn from_node(node: &Node) -> EBResult<{name}> {{\n        \
+                     try!(check_label(node, \"{label}\"));\n        \
+                     Ok({name} {{\n            id: node.id.clone(),\n{conversions}        }})\n    \
+                 }}\n\
+             }}\n\n",
+            label=label, name=name, fields=fields, conversions=conversions).as_bytes()).unwrap();
+
+        labels.push((label, name));
+    }
+
+    // An enum of all of them to convert nodes of any label
+    f.write_all(b"#[derive(Debug,Clone,PartialEq)]\npub enum Model {\n").unwrap();
+    for &(_, ref name) in &labels {
+        f.write_all(format!("    {name}({name}),\n", name=name).as_bytes()).unwrap();
+    }
+    f.write_all(b"}\n\nimpl Model {\n    \
+                  pub fn from_node(node: &Node) -> EBResult<Model> {\n        \
+                      Ok(match &*node.label {\n").unwrap();
+    for &(ref label, ref name) in &labels {
+        f.write_all(format!("            \"{label}\" => Model::{name}(try!({name}::from_node(node))),\n",
+                            label=label, name=name).as_bytes()).unwrap();
+    }
+    f.write_all(b"            _ => return Err(format!(\"Unknown label {}\", node.label).into()),\n        \
+                  })\n    }\n}\n").unwrap();
+}
+
+
 fn main() {
     let mut f = File::create(&Path::new("src/dictionary.rs")).unwrap();
     let mut schema_dir = get_top_dir(get_target_dir().as_path()).to_path_buf();
     schema_dir.push(Path::new("src/gdcdictionary/gdcdictionary/schemas"));
+    // Listing inputs stops cargo rerunning this whenever any file changes
+    println!("cargo:rerun-if-changed={}", schema_dir.display());
 
-    let entries = WalkDir::new(schema_dir).into_iter()
+    let entries = WalkDir::new(&schema_dir).into_iter()
         .filter_map(|e| e.ok())
         .filter(|e| e.path().file_name().is_some())
         .filter(|e| e.path().file_name().unwrap().to_str().is_some())
         .filter(|e| format!("{}", e.path().display()).ends_with(".yaml"))
-        .filter(|e| !format!("{}", e.path().display()).contains("metaschema.yaml"))
-        .filter(|e| !format!("{}", e.path().display()).contains("projects"));
+        .filter(|e| !format!("{}", e.path().display()).contains("metaschema.yaml"));
 
     let mut schemas = Vec::new();
+    let mut project_schemas = Vec::new();
+    let mut sources = Vec::new();
 
     for entry in entries {
-        schemas.push(format!("    include_str!(\"{}\"),\n", entry.path().to_str().unwrap()));
-    }
+        let include = format!("    include_str!(\"{}\"),\n", entry.path().to_str().unwrap());
+        let relative = entry.path().strip_prefix(&schema_dir).unwrap();
 
-    f.write_all(&*format!("pub static SCHEMAS: [&'static str; {}] = [\n", schemas.len())
-                .as_bytes()).unwrap();
+        // Project specific overrides are kept separate from the base schemas
+        if relative.components().any(|part| part.as_os_str() == "projects") {
+            project_schemas.push(include);
+            continue
+        }
+        schemas.push(include);
 
-    for schema in schemas {
-        f.write_all(schema.as_bytes()).unwrap();
+        let mut source = String::new();
+        File::open(entry.path()).unwrap().read_to_string(&mut source).unwrap();
+        sources.push(source);
     }
-    f.write_all(b"];").unwrap();
+    let resolver = Resolver::new(&sources).unwrap_or_else(|err| panic!("{}", err));
+    let node_schemas = node_schemas(&resolver);
+
+    write_schemas(&mut f, "SCHEMAS", &schemas);
+    write_schemas(&mut f, "PROJECT_SCHEMAS", &project_schemas);
+
+    let mut models = File::create(&Path::new("src/models.rs")).unwrap();
+    write_models(&mut models, &node_schemas);
+
+    let mut overrides_source = String::new();
+    let overrides_path = get_top_dir(get_target_dir().as_path()).join("src/table_names.yaml");
+    println!("cargo:rerun-if-changed={}", overrides_path.display());
+    File::open(&overrides_path).unwrap().read_to_string(&mut overrides_source).unwrap();
+    let overrides = YamlLoader::load_from_str(&*overrides_source).unwrap().pop().unwrap_or(Yaml::Null);
+
+    let mut table_names = File::create(&Path::new("src/table_names.rs")).unwrap();
+    write_table_names(&mut table_names, &node_schemas, &overrides);
 }
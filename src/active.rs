@@ -0,0 +1,65 @@
+use ::types::*;
+
+
+/// Node types only in the legacy index
+fn legacy_only_labels() -> Vec<String> {
+    vec!["file".into(), "archive".into()]
+}
+
+
+/// Every data, metadata and index file node type takes the place of
+/// the single legacy `file` label
+fn active_file_labels(datamodel: &Datamodel) -> Vec<String> {
+    let legacy_only = legacy_only_labels();
+    let mut labels = datamodel.node_types.values()
+        .filter(|node_type| match node_type.category {
+            NodeCategory::DataFile | NodeCategory::MetadataFile | NodeCategory::IndexFile => true,
+            _ => false,
+        })
+        .filter(|node_type| !legacy_only.contains(&node_type.label))
+        .map(|node_type| node_type.label.clone())
+        .collect::<Vec<_>>();
+    labels.sort();
+    labels
+}
+
+
+/// Biospecimen entities (and cases) that files are associated with
+fn active_associated_entities() -> Vec<String> {
+    vec![
+        "aliquot".into(), "analyte".into(), "portion".into(),
+        "read_group".into(), "sample".into(), "slide".into(), "case".into(),
+    ]
+}
+
+
+impl Options {
+    pub fn active_defaults(datamodel: Datamodel) -> Options {
+        let file_labels = active_file_labels(&datamodel);
+        let entities = active_associated_entities();
+        Options {
+            case_to_file_paths: datamodel.label_paths("case", &file_labels, &entities),
+            datamodel: datamodel,
+            file_labels: file_labels,
+            possible_associated_entites: entities,
+            index_file_extensions: vec![".bai".into(), ".tbi".into()],
+            index_type: IndexType::Active,
+            deterministic: false,
+        }
+    }
+}
+
+
+impl CachingOptions {
+    /// Supplements are node types of their own in the active index,
+    /// so unlike the legacy defaults there are no supplement regexes
+    pub fn active_defaults(datamodel: &Datamodel) -> CachingOptions {
+        let mut options = CachingOptions::new();
+        options.file_labels = active_file_labels(datamodel);
+        options.possible_associated_entites = active_associated_entities();
+        options.case_to_file_paths = datamodel.label_paths(
+            "case", &options.file_labels, &options.possible_associated_entites);
+        options.index_file_extensions = vec![".bai".into(), ".tbi".into()];
+        options
+    }
+}
@@ -0,0 +1,438 @@
+//! Compares the documents of two builds.
+//!
+//! Documents are matched by their id field (e.g. `case_id`), and for
+//! every document in both builds the JSON paths whose values differ
+//! are listed, e.g. `files[2].data_format`.  Lists are compared item
+//! by item, so builds should be made with `--deterministic` to keep
+//! reordered lists from showing up as changes.  The paths are also
+//! counted per field, with the list indexes left out
+//! (`files[].data_format`), to summarize what changed overall.
+//!
+//! The builds are read a document at a time.  When both are in order
+//! of id, as `--deterministic` builds are, they're merged by id so
+//! neither is held in memory.  Otherwise the old build is copied to a
+//! temporary file and only its ids and their offsets in the file are
+//! kept, to look up the old version of each new document.
+
+use serde_json::{self, Value};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use ::errors::EBResult;
+use ::output::read_documents;
+use ::selection::random_seed;
+use ::types::*;
+
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum DocumentChange {
+    Added(String),
+    Removed(String),
+    Changed { id: String, paths: Vec<String> },
+}
+
+
+#[derive(Debug)]
+pub struct BuildDiff {
+    pub changes: Vec<DocumentChange>,
+    /// Number of changed documents with a difference in each field
+    pub field_counts: BTreeMap<String, usize>,
+    /// Number of documents in both builds that are the same
+    pub unchanged: usize,
+}
+
+
+impl DocumentChange {
+    pub fn id(&self) -> &str {
+        match *self {
+            DocumentChange::Added(ref id) => id,
+            DocumentChange::Removed(ref id) => id,
+            DocumentChange::Changed { ref id, .. } => id,
+        }
+    }
+}
+
+
+impl fmt::Display for DocumentChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DocumentChange::Added(ref id) => write!(f, "{}: added", id),
+            DocumentChange::Removed(ref id) => write!(f, "{}: removed", id),
+            DocumentChange::Changed { ref id, ref paths } => write!(f, "{}: changed {}", id, paths.join(", ")),
+        }
+    }
+}
+
+
+impl BuildDiff {
+    fn new() -> BuildDiff {
+        BuildDiff { changes: Vec::new(), field_counts: BTreeMap::new(), unchanged: 0 }
+    }
+
+    /// Adds the paths at which a document in both builds differs
+    fn document(&mut self, id: String, paths: Vec<String>) {
+        if paths.is_empty() {
+            self.unchanged += 1;
+            return
+        }
+
+        // Count each field once per document
+        for field in paths.iter().map(|path| field(path)).collect::<BTreeSet<_>>() {
+            *self.field_counts.entry(field).or_insert(0) += 1;
+        }
+        self.changes.push(DocumentChange::Changed { id: id, paths: paths });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Counts of the added, removed and changed documents
+    pub fn counts(&self) -> (usize, usize, usize) {
+        let mut counts = (0, 0, 0);
+        for change in &self.changes {
+            match *change {
+                DocumentChange::Added(_) => counts.0 += 1,
+                DocumentChange::Removed(_) => counts.1 += 1,
+                DocumentChange::Changed { .. } => counts.2 += 1,
+            }
+        }
+        counts
+    }
+}
+
+
+impl fmt::Display for BuildDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for change in &self.changes {
+            try!(writeln!(f, "{}", change));
+        }
+        if !self.field_counts.is_empty() {
+            try!(writeln!(f, ""));
+            for (field, count) in &self.field_counts {
+                try!(writeln!(f, "{:>8} {}", count, field));
+            }
+        }
+        let (added, removed, changed) = self.counts();
+        writeln!(f, "{} added, {} removed, {} changed, {} unchanged", added, removed, changed, self.unchanged)
+    }
+}
+
+
+fn child_path(path: &str, key: &str) -> String {
+    match path.is_empty() {
+        true => key.to_string(),
+        false => format!("{}.{}", path, key),
+    }
+}
+
+
+/// Adds the paths below `path` at which `old` and `new` differ
+fn diff_values(path: &str, old: Option<&Value>, new: Option<&Value>, paths: &mut Vec<String>) {
+    match (old, new) {
+        (Some(&Value::Object(ref old)), Some(&Value::Object(ref new))) => {
+            for key in old.keys().chain(new.keys()).collect::<BTreeSet<_>>() {
+                diff_values(&*child_path(path, key), old.get(key), new.get(key), paths);
+            }
+        },
+        (Some(&Value::Array(ref old)), Some(&Value::Array(ref new))) => {
+            for i in 0..old.len().max(new.len()) {
+                diff_values(&*format!("{}[{}]", path, i), old.get(i), new.get(i), paths);
+            }
+        },
+        (old, new) => if old != new {
+            paths.push(path.to_string());
+        },
+    }
+}
+
+
+/// The field a path is in, i.e. the path without list indexes
+fn field(path: &str) -> String {
+    let mut field = String::new();
+    let mut in_index = false;
+    for c in path.chars() {
+        match c {
+            '[' => { in_index = true; field.push_str("[]"); },
+            ']' => in_index = false,
+            c if !in_index => field.push(c),
+            _ => {},
+        }
+    }
+    field
+}
+
+
+/// The id of a document, which is a string in `id_field`
+fn document_id(doc: &Doc, id_field: &str) -> EBResult<String> {
+    match doc.get(id_field).and_then(|id| id.as_str()) {
+        Some(id) => Ok(id.to_string()),
+        None => Err(format!("Document without {}", id_field).into()),
+    }
+}
+
+
+/// The documents of a build with their ids, as long as they're in
+/// order of id
+struct ById<'a, I> {
+    docs: I,
+    id_field: &'a str,
+    last: Option<String>,
+    sorted: bool,
+}
+
+
+impl<'a, I: Iterator<Item=EBResult<Doc>>> ById<'a, I> {
+    fn new(docs: I, id_field: &'a str) -> ById<'a, I> {
+        ById { docs: docs, id_field: id_field, last: None, sorted: true }
+    }
+
+    /// The next document and its id, failing on missing or repeated
+    /// ids.  An id out of order ends the documents and clears `sorted`
+    fn next(&mut self) -> EBResult<Option<(String, Doc)>> {
+        let doc = match self.docs.next() {
+            Some(doc) => try!(doc),
+            None => return Ok(None),
+        };
+        let id = try!(document_id(&doc, self.id_field));
+        match self.last.as_ref().map(|last| last.cmp(&id)) {
+            Some(Ordering::Equal) => return Err(format!("Document {} appears more than once", id).into()),
+            Some(Ordering::Greater) => {
+                self.sorted = false;
+                return Ok(None)
+            },
+            _ => {},
+        }
+        self.last = Some(id.clone());
+        Ok(Some((id, doc)))
+    }
+}
+
+
+/// Documents copied to a temporary file, which is removed when dropped
+struct Spill {
+    path: PathBuf,
+    file: File,
+    /// The offset and length of each document in the file, by id
+    offsets: HashMap<String, (u64, usize)>,
+}
+
+
+impl Spill {
+    fn create() -> EBResult<Spill> {
+        let path = env::temp_dir().join(format!("esbuild-diff-{:x}.jsonl", random_seed()));
+        let file = try!(OpenOptions::new().read(true).write(true).create_new(true).open(&path));
+        Ok(Spill { path: path, file: file, offsets: HashMap::new() })
+    }
+
+    /// Copies the documents to the file, failing on repeated ids
+    fn write<I: Iterator<Item=EBResult<Doc>>>(&mut self, docs: I, id_field: &str) -> EBResult<()> {
+        let mut writer = BufWriter::new(&self.file);
+        let mut offset = 0;
+        for doc in docs {
+            let doc = try!(doc);
+            let id = try!(document_id(&doc, id_field));
+            let line = serde_json::to_string(&doc).unwrap();
+            try!(writer.write_all(line.as_bytes()));
+            if self.offsets.insert(id.clone(), (offset, line.len())).is_some() {
+                return Err(format!("Document {} appears more than once", id).into())
+            }
+            offset += line.len() as u64;
+        }
+        try!(writer.flush());
+        Ok(())
+    }
+
+    /// Reads back the document with the id, which is then forgotten
+    fn take(&mut self, id: &str) -> EBResult<Option<Doc>> {
+        let (offset, len) = match self.offsets.remove(id) {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+        let mut line = vec![0; len];
+        try!((&self.file).seek(SeekFrom::Start(offset)));
+        try!((&self.file).read_exact(&mut line));
+        Ok(Some(try!(serde_json::from_slice(&line).map_err(|err| format!("Unable to read back {}: {}", id, err)))))
+    }
+}
+
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+
+/// The paths at which two versions of a document differ
+fn diff_documents(old: &Doc, new: &Doc) -> Vec<String> {
+    let mut paths = Vec::new();
+    for key in old.keys().chain(new.keys()).collect::<BTreeSet<_>>() {
+        diff_values(key, old.get(key), new.get(key), &mut paths);
+    }
+    paths
+}
+
+
+/// Merges builds that are both in order of id.  Returns None as soon
+/// as either turns out not to be
+fn diff_sorted<O, N>(old: O, new: N, id_field: &str) -> EBResult<Option<BuildDiff>>
+    where O: Iterator<Item=EBResult<Doc>>, N: Iterator<Item=EBResult<Doc>>
+{
+    let mut old = ById::new(old, id_field);
+    let mut new = ById::new(new, id_field);
+    let (mut old_next, mut new_next) = (try!(old.next()), try!(new.next()));
+
+    let mut diff = BuildDiff::new();
+    loop {
+        if !old.sorted || !new.sorted {
+            return Ok(None)
+        }
+        let order = match (&old_next, &new_next) {
+            (&None, &None) => break,
+            (&Some(_), &None) => Ordering::Less,
+            (&None, &Some(_)) => Ordering::Greater,
+            (&Some((ref old_id, _)), &Some((ref new_id, _))) => old_id.cmp(new_id),
+        };
+        match order {
+            Ordering::Less => {
+                let (id, _) = old_next.unwrap();
+                diff.changes.push(DocumentChange::Removed(id));
+                old_next = try!(old.next());
+            },
+            Ordering::Greater => {
+                let (id, _) = new_next.unwrap();
+                diff.changes.push(DocumentChange::Added(id));
+                new_next = try!(new.next());
+            },
+            Ordering::Equal => {
+                let ((id, old_doc), (_, new_doc)) = (old_next.unwrap(), new_next.unwrap());
+                diff.document(id, diff_documents(&old_doc, &new_doc));
+                old_next = try!(old.next());
+                new_next = try!(new.next());
+            },
+        }
+    }
+    Ok(Some(diff))
+}
+
+
+/// Diffs builds in any order, by copying the old one to a temporary
+/// file and looking up each new document in it
+fn diff_indexed<O, N>(old: O, new: N, id_field: &str) -> EBResult<BuildDiff>
+    where O: Iterator<Item=EBResult<Doc>>, N: Iterator<Item=EBResult<Doc>>
+{
+    let mut spill = try!(Spill::create());
+    try!(spill.write(old, id_field));
+
+    let mut diff = BuildDiff::new();
+    let mut seen = HashSet::new();
+    for new_doc in new {
+        let new_doc = try!(new_doc);
+        let id = try!(document_id(&new_doc, id_field));
+        if !seen.insert(id.clone()) {
+            return Err(format!("Document {} appears more than once", id).into())
+        }
+        match try!(spill.take(&id)) {
+            Some(old_doc) => diff.document(id, diff_documents(&old_doc, &new_doc)),
+            None => diff.changes.push(DocumentChange::Added(id)),
+        }
+    }
+    diff.changes.extend(spill.offsets.keys().map(|id| DocumentChange::Removed(id.clone())));
+
+    // List the changes in order of id, as merging sorted builds does
+    diff.changes.sort_by(|a, b| a.id().cmp(b.id()));
+    Ok(diff)
+}
+
+
+/// Returns the changes from the documents of the `old` build to those
+/// of the `new` one, matching documents on `id_field`.  Builds in
+/// order of id are merged, others are diffed through a temporary file
+pub fn diff<P: AsRef<Path>>(old_path: P, new_path: P, id_field: &str) -> EBResult<BuildDiff> {
+    let (old, new) = (try!(read_documents(&old_path)), try!(read_documents(&new_path)));
+    if let Some(diff) = try!(diff_sorted(old, new, id_field)) {
+        return Ok(diff)
+    }
+    info!("Builds aren't in order of {}, diffing through a temporary file", id_field);
+    diff_indexed(try!(read_documents(&old_path)), try!(read_documents(&new_path)), id_field)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use super::*;
+
+    fn docs(lines: &[&str]) -> Vec<EBResult<Doc>> {
+        lines.iter().map(|line| Ok(serde_json::from_str(line).unwrap())).collect()
+    }
+
+    fn paths(old: &str, new: &str) -> Vec<String> {
+        diff_documents(&serde_json::from_str(old).unwrap(), &serde_json::from_str(new).unwrap())
+    }
+
+    #[test]
+    fn paths_point_into_objects_and_lists() {
+        assert_eq!(paths(r#"{"a": 1, "b": {"c": "x"}}"#, r#"{"a": 1, "b": {"c": "y"}}"#), vec!["b.c"]);
+        assert_eq!(paths(r#"{"files": [{"f": 1}, {"f": 2}]}"#, r#"{"files": [{"f": 1}, {"f": 3}, {"f": 4}]}"#),
+                   vec!["files[1].f", "files[2]"]);
+        assert_eq!(paths(r#"{"a": 1}"#, r#"{"b": 1}"#), vec!["a", "b"]);
+        assert_eq!(paths(r#"{"a": [1]}"#, r#"{"a": {"0": 1}}"#), vec!["a"]);
+        assert!(paths(r#"{"a": [1, {"b": null}]}"#, r#"{"a": [1, {"b": null}]}"#).is_empty());
+        assert_eq!(field("files[12].cases[0].project.project_id"), "files[].cases[].project.project_id");
+    }
+
+    const OLD: [&'static str; 3] = [
+        r#"{"case_id": "a", "files": [{"size": 1}]}"#,
+        r#"{"case_id": "b", "state": "live"}"#,
+        r#"{"case_id": "c"}"#,
+    ];
+
+    const NEW: [&'static str; 3] = [
+        r#"{"case_id": "a", "files": [{"size": 2}]}"#,
+        r#"{"case_id": "b", "state": "live"}"#,
+        r#"{"case_id": "d"}"#,
+    ];
+
+    fn expected() -> Vec<DocumentChange> {
+        vec![
+            DocumentChange::Changed { id: "a".into(), paths: vec!["files[0].size".into()] },
+            DocumentChange::Removed("c".into()),
+            DocumentChange::Added("d".into()),
+        ]
+    }
+
+    #[test]
+    fn sorted_builds_are_merged() {
+        let diff = diff_sorted(docs(&OLD).into_iter(), docs(&NEW).into_iter(), "case_id").unwrap().unwrap();
+        assert_eq!(diff.changes, expected());
+        assert_eq!(diff.unchanged, 1);
+        assert_eq!(diff.field_counts.get("files[].size"), Some(&1));
+    }
+
+    #[test]
+    fn unsorted_builds_are_indexed() {
+        let new = [NEW[2], NEW[0], NEW[1]];
+        assert!(diff_sorted(docs(&OLD).into_iter(), docs(&new).into_iter(), "case_id").unwrap().is_none());
+
+        let diff = diff_indexed(docs(&OLD).into_iter(), docs(&new).into_iter(), "case_id").unwrap();
+        assert_eq!(diff.changes, expected());
+        assert_eq!(diff.unchanged, 1);
+    }
+
+    #[test]
+    fn repeated_and_missing_ids_are_errors() {
+        let repeated = [OLD[0], OLD[0]];
+        assert!(diff_sorted(docs(&repeated).into_iter(), docs(&NEW).into_iter(), "case_id").is_err());
+        assert!(diff_indexed(docs(&repeated).into_iter(), docs(&NEW).into_iter(), "case_id").is_err());
+        assert!(diff_indexed(docs(&NEW).into_iter(), docs(&repeated).into_iter(), "case_id").is_err());
+        assert!(diff_sorted(docs(&OLD).into_iter(), docs(&NEW).into_iter(), "file_id").is_err());
+        assert!(diff_indexed(docs(&OLD).into_iter(), docs(&NEW).into_iter(), "file_id").is_err());
+    }
+}
@@ -1,15 +1,20 @@
+use ::errors::EBResult;
 use ::types::*;
 use ::graph::CachedGraph;
+use std::collections::{HashSet, VecDeque};
 
 
 impl TypeTree {
-    pub fn new<S>(label: S, title: S, correlation: Correlation) -> TypeTree
+    /// Creates a tree rooted at the label.  The correlation of each
+    /// child is taken from the dictionary by correlate()
+    pub fn new<S>(label: S, title: S) -> TypeTree
         where S: Into<String>
     {
         TypeTree {
             label: label.into(),
             title: title.into(),
-            correlation: correlation,
+            correlation: Correlation::ToMany,
+            correlation_override: None,
             children: Vec::new(),
         }
     }
@@ -19,14 +24,42 @@ impl TypeTree {
         self.children.push(child);
         self
     }
+
+    /// Sets the correlation to its parent instead of taking it from
+    /// the dictionary, for node types the dictionary doesn't link
+    pub fn correlation(mut self, correlation: Correlation) -> TypeTree
+    {
+        self.correlation_override = Some(correlation);
+        self
+    }
+
+    /// Sets the correlation of every child from the multiplicity of
+    /// the links between it and its parent in the dictionary, unless
+    /// it's overridden.  Fails on a child the dictionary doesn't link
+    pub fn correlate(mut self, datamodel: &Datamodel) -> EBResult<TypeTree>
+    {
+        let mut children = Vec::new();
+        for mut child in self.children {
+            child.correlation = match (child.correlation_override.clone(),
+                                       datamodel.correlation(&*self.label, &*child.label)) {
+                (Some(correlation), _) | (None, Some(correlation)) => correlation,
+                (None, None) => return Err(format!(
+                    "No link from {} to {} in the dictionary, set the correlation of {}",
+                    self.label, child.label, child.label).into()),
+            };
+            children.push(try!(child.correlate(datamodel)));
+        }
+        self.children = children;
+        Ok(self)
+    }
 }
 
 
 impl<'a> NodeTree<'a> {
-    pub fn new(node: &'a Node, label: &'a str, correlation: Correlation) -> NodeTree<'a> {
+    pub fn new(node: &'a Node, title: &'a str, correlation: Correlation) -> NodeTree<'a> {
         NodeTree {
             node: node,
-            title: label,
+            title: title,
             correlation: correlation,
             children: Vec::new(),
         }
@@ -72,59 +105,218 @@ impl<'a> NodeTree<'a> {
 }
 
 pub fn sample_type_tree() -> TypeTree {
-    TypeTree::new("sample", "samples", Correlation::ToMany)
-        .child(TypeTree::new("annotation", "annotations", Correlation::ToMany))
-        .child(TypeTree::new("aliquot", "aliquots", Correlation::ToMany))
-        .child(TypeTree::new("portion", "portions", Correlation::ToMany)
-               .child(TypeTree::new("annotation", "annotations", Correlation::ToMany))
-               .child(TypeTree::new("analyte", "analytes", Correlation::ToMany)
-                      .child(TypeTree::new("annotation", "annotations", Correlation::ToMany))
-                      .child(TypeTree::new("aliquot", "aliquot", Correlation::ToMany)
-                             .child(TypeTree::new("annotation", "annotations", Correlation::ToMany)))
-                             .child(TypeTree::new("center", "center", Correlation::ToOne)))
-               .child(TypeTree::new("slide", "slides", Correlation::ToMany)
-                      .child(TypeTree::new("annotation", "annotations", Correlation::ToMany))))
+    TypeTree::new("sample", "samples")
+        .child(TypeTree::new("annotation", "annotations"))
+        .child(TypeTree::new("aliquot", "aliquots"))
+        .child(TypeTree::new("portion", "portions")
+               .child(TypeTree::new("annotation", "annotations"))
+               .child(TypeTree::new("analyte", "analytes")
+                      .child(TypeTree::new("annotation", "annotations"))
+                      .child(TypeTree::new("aliquot", "aliquot")
+                             .child(TypeTree::new("annotation", "annotations")))
+                             .child(TypeTree::new("center", "center").correlation(Correlation::ToOne)))
+               .child(TypeTree::new("slide", "slides")
+                      .child(TypeTree::new("annotation", "annotations"))))
 }
 
 
 pub fn file_type_tree() -> TypeTree {
-        TypeTree::new("file", "files", Correlation::ToMany)
-        .child(TypeTree::new("annotation", "annotations", Correlation::ToOne))
-        .child(TypeTree::new("archive", "archive", Correlation::ToOne))
-        .child(TypeTree::new("center", "center", Correlation::ToOne))
-        .child(TypeTree::new("data_format", "data_format", Correlation::ToOne))
-        .child(TypeTree::new("data_subtype", "data_type", Correlation::ToOne)
-               .child(TypeTree::new("data_type", "data_category", Correlation::ToOne)))
-        .child(TypeTree::new("experimental_strategy", "experimental_strategy", Correlation::ToOne))
-        .child(TypeTree::new("case", "cases", Correlation::ToMany))
-        .child(TypeTree::new("platform", "platform", Correlation::ToOne))
-        .child(TypeTree::new("tag", "tags", Correlation::ToMany))
-        .child(TypeTree::new("file", "metadata_files", Correlation::ToMany))
+        TypeTree::new("file", "files")
+        .child(TypeTree::new("annotation", "annotations"))
+        .child(TypeTree::new("archive", "archive"))
+        .child(TypeTree::new("center", "center"))
+        .child(TypeTree::new("data_format", "data_format"))
+        .child(TypeTree::new("data_subtype", "data_type")
+               .child(TypeTree::new("data_type", "data_category")))
+        .child(TypeTree::new("experimental_strategy", "experimental_strategy"))
+        .child(TypeTree::new("case", "cases"))
+        .child(TypeTree::new("platform", "platform"))
+        .child(TypeTree::new("tag", "tags"))
+        .child(TypeTree::new("file", "metadata_files"))
 }
 
 
 pub fn case_type_tree() -> TypeTree {
-    TypeTree::new("case", "cases", Correlation::ToMany)
+    TypeTree::new("case", "cases")
         .child(sample_type_tree())
-        .child(TypeTree::new("annotation", "annotations", Correlation::ToMany))
-        .child(TypeTree::new("project", "project", Correlation::ToOne))
-        .child(TypeTree::new("program", "program", Correlation::ToOne)
-               .child(TypeTree::new("program", "program", Correlation::ToOne)))
-        .child(TypeTree::new("file", "files", Correlation::ToMany))
-        .child(TypeTree::new("tissue_source_site", "tissue_source_site", Correlation::ToOne))
-        .child(sample_type_tree().child(file_type_tree()))
-        .child(TypeTree::new("demographic", "demographic", Correlation::ToOne))
-        .child(TypeTree::new("exposure", "exposures", Correlation::ToMany))
-        .child(TypeTree::new("diagnosis", "diagnoses", Correlation::ToMany)
-               .child(TypeTree::new("treatment", "treatments", Correlation::ToMany)))
-        .child(TypeTree::new("family_history", "family_history", Correlation::ToMany))
+        .child(TypeTree::new("annotation", "annotations"))
+        .child(TypeTree::new("project", "project"))
+        .child(TypeTree::new("program", "program").correlation(Correlation::ToOne)
+               .child(TypeTree::new("program", "program").correlation(Correlation::ToOne)))
+        .child(TypeTree::new("tissue_source_site", "tissue_source_site"))
+        .child(TypeTree::new("demographic", "demographic"))
+        .child(TypeTree::new("exposure", "exposures"))
+        .child(TypeTree::new("diagnosis", "diagnoses")
+               .child(TypeTree::new("treatment", "treatments")))
+        .child(TypeTree::new("family_history", "family_history"))
+}
+
+
+/// Walks up from a biospecimen entity until reaching the case it
+/// was derived from
+fn owning_case<'a>(options: &Options, graph: &'a CachedGraph, entity: &'a Node) -> Option<&'a Node> {
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(entity);
+
+    while let Some(node) = queue.pop_front() {
+        if node.label == "case" {
+            return Some(node)
+        }
+        if !visited.insert(&node.id) {
+            continue
+        }
+        for parent in graph.outgoing(&node.id) {
+            if options.possible_associated_entites.contains(&parent.label) {
+                queue.push_back(parent)
+            }
+        }
+    }
+    None
+}
+
+
+/// Returns the nearest biospecimen or case entities that the file is
+/// `data_from`.  If the file was only derived from other files, the
+/// entities of the nearest source files are used instead.
+pub fn associated_entities(options: &Options, graph: &CachedGraph, file: &Node) -> Vec<Doc> {
+    let mut visited = HashSet::new();
+    let mut sources = vec![file];
+    let mut entities = Vec::new();
+
+    while entities.is_empty() && !sources.is_empty() {
+        let mut next_sources = Vec::new();
+        for source in sources {
+            if !visited.insert(&source.id) {
+                continue
+            }
+            for neighbor in graph.outgoing_labeled(&source.id, "data_from") {
+                if options.possible_associated_entites.contains(&neighbor.label) {
+                    entities.push(neighbor)
+                } else if options.file_labels.contains(&neighbor.label) {
+                    next_sources.push(neighbor)
+                }
+            }
+        }
+        sources = next_sources;
+    }
+
+    if options.deterministic {
+        sort_by_id(&mut entities);
+    }
+
+    entities.iter().map(|entity| {
+        let case_id = owning_case(options, graph, entity).map(|case| case.id.clone());
+        doc!({
+            "entity_type"; entity.label,
+            "entity_id"; entity.id,
+            "entity_submitter_id"; entity.props.get("submitter_id"),
+            "case_id"; case_id
+        })
+    }).collect()
+}
+
+
+/// Sorts nodes by id, used to make the order of lists in documents
+/// independent of the order of the graph's maps
+fn sort_by_id(nodes: &mut Vec<&Node>) {
+    nodes.sort_by(|a, b| a.id.cmp(&b.id));
+}
+
+
+/// Supplement files are grouped under their pluralized category
+/// (e.g. `clinical_supplements`) instead of the title in the type tree
+fn child_title(graph: &CachedGraph, child: &NodeTree) -> String {
+    match graph.supplement_type(&child.node.id) {
+        Some(category) => format!("{}s", category),
+        None => child.title.to_string(),
+    }
+}
+
+
+/// Adds the supplements below the root of the tree, which are
+/// attached to the case instead of where they're found
+fn collect_supplements<'a, 'b>(graph: &CachedGraph, tree: &'b NodeTree<'a>,
+                               supplements: &mut Vec<&'b NodeTree<'a>>) {
+    for child in tree.children.iter().filter(|child| !graph.is_index_file(&child.node.id)) {
+        if graph.supplement_type(&child.node.id).is_some() {
+            supplements.push(child);
+        }
+        collect_supplements(graph, child, supplements);
+    }
 }
 
 
 pub fn denormalize_tree(options: &Options, graph: &CachedGraph, tree: &NodeTree) -> Doc {
     let mut doc = tree.node.get_base_doc(options);
-    for child in &tree.children {
+
+    if let Some(category) = graph.supplement_type(&tree.node.id) {
+        setitem!(doc, "supplement_type".to_string(), category)
+    }
+
+    if options.file_labels.contains(&tree.node.label) {
+        setitem!(doc, "associated_entities".to_string(), associated_entities(options, graph, tree.node))
+    }
+
+    let mut index_files = graph.index_files_of(&tree.node.id);
+    if options.deterministic {
+        sort_by_id(&mut index_files);
+    }
+    for index_file in index_files {
+        append!(doc, "index_files".to_string(), index_file.get_base_doc(options))
+    }
+
+    // Index files are only reachable through the file they index,
+    // and supplements are attached to the case
+    let mut children = tree.children.iter()
+        .filter(|child| !graph.is_index_file(&child.node.id) && graph.supplement_type(&child.node.id).is_none())
+        .collect::<Vec<_>>();
+    if options.deterministic {
+        children.sort_by(|a, b| a.node.id.cmp(&b.node.id));
+    }
+    for child in children {
         setitem!(doc, child.title.to_string(), denormalize_tree(options, graph, child))
     }
     doc
 }
+
+
+
+/// Denormalizes the case tree, then adds every file found along the
+/// cached case to file paths, each denormalized with the file tree.
+/// Supplements found anywhere in the trees are added to the case
+pub fn denormalize_case(options: &Options, graph: &CachedGraph, case_tree: &TypeTree,
+                        file_tree: &TypeTree, case: &Node) -> Doc {
+    let tree = NodeTree::construct(graph, case_tree, case);
+    let mut doc = denormalize_tree(options, graph, &tree);
+
+    let mut files = graph.nodes_along_paths(&case.id, &options.case_to_file_paths);
+    if options.deterministic {
+        sort_by_id(&mut files);
+    }
+    let file_trees = files.into_iter()
+        .filter(|file| !graph.is_index_file(&file.id))
+        .map(|file| NodeTree::construct(graph, file_tree, file))
+        .collect::<Vec<_>>();
+
+    let mut supplements = Vec::new();
+    collect_supplements(graph, &tree, &mut supplements);
+    for file_tree in &file_trees {
+        match graph.supplement_type(&file_tree.node.id) {
+            Some(_) => supplements.push(file_tree),
+            None => append!(doc, file_tree.title.to_string(), denormalize_tree(options, graph, file_tree)),
+        }
+        collect_supplements(graph, file_tree, &mut supplements);
+    }
+
+    if options.deterministic {
+        supplements.sort_by(|a, b| a.node.id.cmp(&b.node.id));
+    }
+    let mut attached = HashSet::new();
+    for supplement in supplements {
+        if attached.insert(&supplement.node.id) {
+            append!(doc, child_title(graph, supplement), denormalize_tree(options, graph, supplement))
+        }
+    }
+    doc
+}
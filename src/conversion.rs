@@ -0,0 +1,84 @@
+//! Conversions from node properties to Rust values, used by the
+//! structs build.rs generates in `models.rs`.
+
+use serde_json::Value;
+
+use ::errors::EBResult;
+use ::types::*;
+
+
+/// A type a property value can be converted to
+pub trait FromValue: Sized {
+    /// Returns None if the value is of the wrong type
+    fn from_value(value: &Value) -> Option<Self>;
+}
+
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Option<String> {
+        value.as_str().map(|value| value.to_string())
+    }
+}
+
+
+impl FromValue for f64 {
+    fn from_value(value: &Value) -> Option<f64> {
+        value.as_f64()
+    }
+}
+
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> Option<bool> {
+        value.as_bool()
+    }
+}
+
+
+impl FromValue for Doc {
+    fn from_value(value: &Value) -> Option<Doc> {
+        value.as_object().cloned()
+    }
+}
+
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> Option<Value> {
+        Some(value.clone())
+    }
+}
+
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: &Value) -> Option<Vec<T>> {
+        value.as_array().and_then(|items| items.iter().map(T::from_value).collect())
+    }
+}
+
+
+/// Errors unless the node has the given label
+pub fn check_label(node: &Node, label: &str) -> EBResult<()> {
+    match node.label == label {
+        true => Ok(()),
+        false => Err(format!("Expected a {} node, {} is a {}", label, node.id, node.label).into()),
+    }
+}
+
+
+/// Converts a property that may be missing or null
+pub fn optional<T: FromValue>(node: &Node, key: &str) -> EBResult<Option<T>> {
+    match node.props.get(key) {
+        None | Some(&Value::Null) => Ok(None),
+        Some(value) => T::from_value(value).map(Some).ok_or_else(|| {
+            format!("{} {}: {} has the wrong type: {}", node.label, node.id, key, value).into()
+        }),
+    }
+}
+
+
+/// Converts a required property, erroring if it's missing or null
+pub fn required<T: FromValue>(node: &Node, key: &str) -> EBResult<T> {
+    try!(optional(node, key)).ok_or_else(|| {
+        format!("{} {}: missing required property {}", node.label, node.id, key).into()
+    })
+}
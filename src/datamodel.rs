@@ -1,9 +1,13 @@
-use std::collections::{HashMap, HashSet, BTreeMap};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use walkdir::WalkDir;
 
 use ::types::*;
-use ::dictionary::SCHEMAS;
-use ::errors::{EBResult, EBError};
-use yaml_rust::{YamlLoader, Yaml};
+use ::dictionary::{SCHEMAS, PROJECT_SCHEMAS};
+use ::errors::EBResult;
+use ::schema::{Resolver, load_yaml, yaml_str};
 
 
 impl<'a> From<&'a str> for NodeCategory {
@@ -23,31 +27,88 @@ impl<'a> From<&'a str> for NodeCategory {
 }
 
 
-impl PropertyType {
-    fn parse(type_str: &str) -> EBResult<PropertyType> {
-        Ok(match &*type_str.to_lowercase() {
-            "bool" => PropertyType::Boolean,
-            "boolean" => PropertyType::Boolean,
-            "datetime" => PropertyType::String,
-            "enum" => PropertyType::String,
-            "float" => PropertyType::Decimal,
-            "integer" => PropertyType::Decimal,
-            "number" => PropertyType::Decimal,
-            "string" => PropertyType::String,
-            _ => return Err(format!("Unknown type: {}", type_str).into()),
+impl NodeCategory {
+    /// The category as written in the dictionary
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            NodeCategory::DataFile => "data_file",
+            NodeCategory::Biospecimen => "biospecimen",
+            NodeCategory::Notation => "notation",
+            NodeCategory::Administrative => "administrative",
+            NodeCategory::Analysis => "analysis",
+            NodeCategory::Clinical => "clinical",
+            NodeCategory::IndexFile => "index_file",
+            NodeCategory::MetadataFile => "metadata_file",
+            NodeCategory::Other => "other",
+        }
+    }
+}
+
+
+impl Multiplicity {
+    fn parse(multiplicity: &str) -> EBResult<Multiplicity> {
+        Ok(match multiplicity {
+            "one_to_one" => Multiplicity::OneToOne,
+            "one_to_many" => Multiplicity::OneToMany,
+            "many_to_one" => Multiplicity::ManyToOne,
+            "many_to_many" => Multiplicity::ManyToMany,
+            _ => return Err(format!("Unknown multiplicity: {}", multiplicity).into()),
         })
     }
+
+    /// The multiplicity of the link when traversed from dst to src
+    pub fn invert(&self) -> Multiplicity {
+        match *self {
+            Multiplicity::OneToOne => Multiplicity::OneToOne,
+            Multiplicity::OneToMany => Multiplicity::ManyToOne,
+            Multiplicity::ManyToOne => Multiplicity::OneToMany,
+            Multiplicity::ManyToMany => Multiplicity::ManyToMany,
+        }
+    }
+
+    /// The multiplicity as written in the dictionary
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Multiplicity::OneToOne => "one_to_one",
+            Multiplicity::OneToMany => "one_to_many",
+            Multiplicity::ManyToOne => "many_to_one",
+            Multiplicity::ManyToMany => "many_to_many",
+        }
+    }
 }
 
-impl SchemaNode {
-    fn new<S>(key: S) -> SchemaNode where S: Into<String> {
-        SchemaNode { key: key.into(), value: None, children: Vec::new() }
+
+impl PropertyType {
+    /// Returns the values allowed by the enums in this type, or None
+    /// if it isn't restricted to an enumeration
+    pub fn enum_values(&self) -> Option<Vec<String>> {
+        match *self {
+            PropertyType::Enum(ref values) => Some(values.clone()),
+            PropertyType::Nullable(ref inner) => inner.enum_values(),
+            PropertyType::Union(ref types) => {
+                let mut values = None;
+                for prop_type in types {
+                    if let Some(type_values) = prop_type.enum_values() {
+                        values.get_or_insert(Vec::new()).extend(type_values);
+                    }
+                }
+                values
+            },
+            _ => None,
+        }
     }
 
-    fn get_kv(&self, key: &str) -> Option<String> {
-        self.get(key).and_then(|node| node.value.clone())
+    /// Returns this type with its enum (or string) restricted to
+    /// `values`, keeping whether it's nullable
+    fn with_enum_values(&self, values: Vec<String>) -> PropertyType {
+        match *self {
+            PropertyType::Nullable(ref inner) => PropertyType::Nullable(Box::new(inner.with_enum_values(values))),
+            _ => PropertyType::Enum(values),
+        }
     }
+}
 
+impl SchemaNode {
     fn print(&self, level: u8) {
         for _ in 0..level + 1 { print!("|--") }
         println!(" {}: {:?}", self.key, self.value);
@@ -56,8 +117,13 @@ impl SchemaNode {
         }
     }
 
-    fn get<'a>(&'a self, key: &str) -> Option<&'a SchemaNode> {
-        self.children.iter().find(|child| child.key == key)
+    /// Returns each set of properties that uniquely identifies a node
+    fn unique_keys(&self) -> Vec<Vec<String>> {
+        self.get("uniqueKeys")
+            .map(|keys| keys.children.iter()
+                 .map(|key| key.children.iter().filter_map(|prop| prop.value.clone()).collect())
+                 .collect())
+            .unwrap_or(Vec::new())
     }
 
     fn node_properties(&self, links: &Vec<EdgeType>) -> EBResult<HashMap<String, PropertyType>> {
@@ -66,26 +132,65 @@ impl SchemaNode {
         let link_names = links.iter().map(|l| l.name.clone()).collect::<HashSet<_>>();
         let prop_nodes = props_node.children.iter().filter(|node| !link_names.contains(&node.key));
         for prop_node in prop_nodes {
-            let prop_type = prop_node.get("type")
-                .and_then(|n| n.value.clone())
-                .map(|type_str| PropertyType::parse(&*type_str))
-                .unwrap_or(Ok(PropertyType::String));
-            props.insert(prop_node.key.clone(), try!(prop_type));
+            let prop_type = try!(prop_node.property_type()
+                .map_err(|err| format!("{}: {}", prop_node.key, err)));
+            props.insert(prop_node.key.clone(), prop_type);
         }
         Ok(props)
     }
 
+    /// Applies a project specific override to the base node type.
+    /// Like the dictionary's own schema tests, keys in the override
+    /// replace those in the base, so properties can be added and
+    /// enums and required lists tightened
+    fn overlay(&self, base: &NodeType) -> EBResult<NodeType> {
+        let mut node_type = base.clone();
+        let link_names = base.links.iter().map(|l| l.name.clone()).collect::<HashSet<_>>();
+
+        if let Some(props_node) = self.get("properties") {
+            for prop_node in props_node.children.iter().filter(|node| !link_names.contains(&node.key)) {
+                let only_enum = prop_node.children.iter().all(|c| c.key == "enum" || c.key == "description");
+                let prop_type = match base.props.get(&prop_node.key) {
+                    // Only tighten the values, e.g. of a nullable enum
+                    Some(base_type) if only_enum && prop_node.get("enum").is_some() =>
+                        base_type.with_enum_values(prop_node.get_list("enum")),
+                    Some(base_type) if prop_node.children.iter().all(|c| c.key == "description") =>
+                        base_type.clone(),
+                    _ => try!(prop_node.property_type().map_err(|err| format!("{}: {}", prop_node.key, err))),
+                };
+                node_type.props.insert(prop_node.key.clone(), prop_type);
+            }
+        }
+
+        if self.get("required").is_some() {
+            node_type.required = self.get_list("required");
+        }
+        if self.get("systemProperties").is_some() {
+            node_type.system_properties = self.get_list("systemProperties");
+        }
+        if self.get("uniqueKeys").is_some() {
+            node_type.unique_keys = self.unique_keys();
+        }
+        Ok(node_type)
+    }
+
     fn node_type(&self) -> EBResult<NodeType> {
         let label = try!(self.get_kv("id").ok_or("missing label"));
         let category_str = &*try!(self.get_kv("category").ok_or("missing category"));
 
         let links = try!(self.edge_types(&label));
+        // A link being required of the source says nothing about the
+        // destination, so backrefs are never required
         let backrefs = links.iter().map(|ref link| EdgeType {
             src_label: link.dst_label.clone(),
             dst_label: link.src_label.clone(),
             name: link.backref.clone(),
             backref: link.name.clone(),
             label: link.label.clone(),
+            multiplicity: link.multiplicity.invert(),
+            required: false,
+            subgroup_exclusive: false,
+            subgroup_required: false,
         }).collect();
 
         let properties = try!(self.node_properties(&links));
@@ -96,142 +201,178 @@ impl SchemaNode {
             category: category_str.into(),
             links: links,
             backrefs: backrefs,
+            required: self.get_list("required"),
+            unique_keys: self.unique_keys(),
+            system_properties: self.get_list("systemProperties"),
         })
     }
 
-    fn edge_type(&self, src_label: &String) -> EBResult<EdgeType> {
+    /// Parses a link, `group` is the entry holding the subgroup the
+    /// link belongs to, if any
+    fn edge_type(&self, src_label: &String, group: Option<&SchemaNode>) -> EBResult<EdgeType> {
         let dst_label = try!(self.get_kv("target_type").ok_or(format!("{:?} missing target", self)));
         let backref = try!(self.get_kv("backref").ok_or(format!("{:?} missing backref", self)));
         let name = try!(self.get_kv("name").ok_or(format!("{:?} missing name", self)));
         let label = try!(self.get_kv("label").ok_or(format!("{:?} missing label", self)));
+        let multiplicity = try!(self.get_kv("multiplicity").ok_or(format!("{:?} missing multiplicity", self)));
 
         Ok(EdgeType {
             src_label: src_label.clone(),
             dst_label: dst_label.to_string(),
             backref: backref.to_string(),
             name: name.to_string(),
-            label: label.to_string()
+            label: label.to_string(),
+            multiplicity: try!(Multiplicity::parse(&*multiplicity)),
+            required: self.get_bool("required"),
+            subgroup_exclusive: group.map_or(false, |group| group.get_bool("exclusive")),
+            subgroup_required: group.map_or(false, |group| group.get_bool("required")),
         })
     }
 
     fn edge_types(&self, src_label: &String) -> EBResult<Vec<EdgeType>> {
-        if !self.get("links").is_some() {
-            return Ok(vec![])
-        }
-
         let mut edges = Vec::new();
-        let links = try!(self.get("links").ok_or("links must be a list"));
-
-        for entry in &links.children {
-            if let Some(subgroup) = entry.get("subgroup") {
-                for link in &subgroup.children {
-                    edges.push(try!(link.edge_type(src_label)));
-                }
-            } else {
-                edges.push(try!(entry.edge_type(src_label)));
-            }
+        for (link, group) in self.links() {
+            edges.push(try!(link.edge_type(src_label, group)));
         }
-
         Ok(edges)
     }
 }
 
-pub struct Resolver {
-    schemas: HashMap<String, Yaml>,
-}
-
 
-pub fn yaml_str<'a>(yaml: &'a Yaml, key: &str) -> EBResult<&'a str> {
-    match yaml[key].as_str() {
-        Some(key_str) => Ok(key_str),
-        None => return Err(EBError::BuildError(
-            format!("unable to parse key '{:}' to string in {:?}", key, yaml))),
-    }
-}
+/// Reads every schema in a dictionary directory, skipping the
+/// metaschema like build.rs does.  Returns the base schemas and the
+/// project specific overrides in `projects/` separately
+pub fn read_schema_dir<P: AsRef<Path>>(dir: P) -> EBResult<(Vec<String>, Vec<String>)> {
+    let dir = dir.as_ref();
+    let mut sources = Vec::new();
+    let mut project_sources = Vec::new();
 
+    for entry in WalkDir::new(dir) {
+        let entry = try!(entry.map_err(io::Error::from));
+        let path = entry.path();
+        let relative = path.strip_prefix(dir).unwrap_or(path);
 
-pub fn load_yaml(source: &str) -> EBResult<Yaml> {
-    Ok(try!(YamlLoader::load_from_str(source))[0].clone())
-}
-
+        if path.extension().map_or(true, |ext| ext != "yaml")
+            || path.file_name().map_or(false, |name| name == "metaschema.yaml")
+        {
+            continue
+        }
 
-impl Resolver {
+        let mut source = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut source));
+        debug!("Read schema {}", path.display());
 
-    fn new() -> EBResult<Resolver> {
-        let mut schemas = HashMap::new();
-        for schema in SCHEMAS.iter() {
-            let schema = try!(load_yaml(&*schema));
-            let label = try!(yaml_str(&schema, "id")).to_string();
-            schemas.insert(label, schema);
+        if relative.components().any(|part| part.as_os_str() == "projects") {
+            project_sources.push(source);
+        } else {
+            sources.push(source);
         }
-        Ok(Resolver { schemas: schemas })
     }
 
-    fn dereference<'a>(&'a self, referrer: &'a Yaml, identifier: &str) -> EBResult<&'a Yaml> {
-        let parts = identifier.split("#/").collect::<Vec<&str>>();
-        let root = try!(parts.get(0).ok_or(format!("Unable to parse id from $ref {}", identifier)));
-        let path = try!(parts.get(1).ok_or(format!("Unable to parse path from $ref {}", identifier)));
-        let id = root.split(".").collect::<Vec<_>>()[0];
-        let schema = match id {
-            "" => referrer,
-            _ => try!(self.schemas.get(id).ok_or(format!("missing schema: {:?}", id))),
-        };
-        let resolution = &schema[*path];
-        Ok(resolution)
+    if sources.is_empty() {
+        return Err(format!("No schemas found in {}", dir.display()).into())
     }
+    Ok((sources, project_sources))
+}
 
-    fn resolve_hash(&self, key: &str, hash: &BTreeMap<Yaml, Yaml>) -> EBResult<Vec<SchemaNode>> {
-        let mut nodes = Vec::new();
-        for (child_key, child_schema) in hash {
-            let child_key = try!(child_key.as_str().ok_or("unable to parse string"));
-
-            if "$ref" == child_key {
-                let child_value = try!(child_schema.as_str().ok_or("ref not a string"));
-                let deref = try!(self.dereference(child_schema, child_value));
-                let children = try!(self.resolve(key, deref)).children;
-                for child in children {
-                    nodes.push(child)
-                }
 
-            } else {
-                let child = try!(self.resolve(child_key, child_schema));
-                nodes.push(child);
-            }
+/// Depth first walk from the last label in `path` to its children,
+/// recording every path that reaches one of the `dsts` labels
+fn walk_label_paths(children: &HashMap<&str, Vec<&str>>, dsts: &[String], via: &[String],
+                    path: &mut Vec<String>, paths: &mut Vec<Vec<String>>)
+{
+    let last = path.last().cloned().unwrap_or_default();
+    for child in children.get(&*last).map(|c| c.as_slice()).unwrap_or(&[]) {
+        let child = child.to_string();
+        if path.contains(&child) {
+            continue
         }
-        Ok(nodes)
+        path.push(child.clone());
+        if dsts.contains(&child) {
+            paths.push(path.clone());
+        } else if via.contains(&child) {
+            walk_label_paths(children, dsts, via, path, paths);
+        }
+        path.pop();
     }
+}
 
-    fn resolve(&self, key: &str, schema: &Yaml) -> EBResult<SchemaNode> {
-        let mut node = SchemaNode::new(key);
 
-        // If yaml is a hash
-        if let Some(hash) = schema.as_hash() {
-            for child in try!(self.resolve_hash(key, hash)) {
-                node.children.push(child)
+impl Datamodel {
+    /// Returns the correlation of `dst` nodes to a `src` node
+    /// according to the multiplicity of the links between them, or
+    /// None if the dictionary doesn't link them
+    pub fn correlation(&self, src: &str, dst: &str) -> Option<Correlation> {
+        let links = self.node_types.get(src).into_iter()
+            .flat_map(|node_type| node_type.links.iter())
+            .filter(|link| link.dst_label == dst);
+        let backrefs = self.node_types.get(dst).into_iter()
+            .flat_map(|node_type| node_type.backrefs.iter())
+            .filter(|backref| backref.src_label == src);
+
+        links.chain(backrefs).map(|edge| edge.correlation()).fold(None, |acc, correlation| {
+            match (acc, correlation) {
+                (Some(Correlation::ToMany), _) => Some(Correlation::ToMany),
+                (_, correlation) => Some(correlation),
             }
+        })
+    }
 
-        // If yaml is a list
-        } else if let Some(vec) = schema.as_vec() {
-            for child_schema in vec {
-                node.children.push(try!(self.resolve(key, child_schema)));
+    /// Returns every path of labels from `src` to one of the `dsts`
+    /// labels, walking from parent to child along the dictionary links
+    /// (e.g. `case <- sample <- aliquot <- file`).  Only labels in
+    /// `via` are walked through on the way to a destination.
+    pub fn label_paths(&self, src: &str, dsts: &[String], via: &[String]) -> Vec<Vec<String>> {
+        let mut children: HashMap<&str, Vec<&str>> = HashMap::new();
+        for node_type in self.node_types.values() {
+            for link in &node_type.links {
+                children.entry(&*link.dst_label).or_insert_with(Vec::new).push(&*link.src_label);
             }
-
-        // Otherwise just save the value
-        } else {
-            node.value = schema.as_str().map(|s| s.to_string())
+        }
+        for labels in children.values_mut() {
+            labels.sort();
+            labels.dedup();
         }
 
-        Ok(node)
+        let mut paths = Vec::new();
+        walk_label_paths(&children, dsts, via, &mut vec![src.to_string()], &mut paths);
+        paths
     }
-}
 
+    /// A datamodel without any node types
+    pub fn empty() -> Datamodel {
+        Datamodel { node_types: HashMap::new(), project_node_types: HashMap::new() }
+    }
 
-impl Datamodel {
+    /// Loads the dictionary embedded at build time
     pub fn new() -> EBResult<Datamodel> {
+        Datamodel::from_sources(&SCHEMAS[..], &PROJECT_SCHEMAS[..])
+    }
+
+    /// Loads the dictionary from a directory of schemas at runtime,
+    /// e.g. a checkout of a gdcdictionary branch
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> EBResult<Datamodel> {
+        let (sources, project_sources) = try!(read_schema_dir(dir));
+        Datamodel::from_sources(&sources, &project_sources)
+    }
+
+    /// Returns the node type for nodes with the label in the given
+    /// project, including any project specific overrides
+    pub fn node_type_for_project(&self, label: &str, project_id: Option<&str>) -> Option<&NodeType> {
+        project_id
+            .and_then(|project_id| self.project_node_types.get(project_id))
+            .and_then(|node_types| node_types.get(label))
+            .or_else(|| self.node_types.get(label))
+    }
+
+    /// Loads the dictionary from the YAML source of each schema and of
+    /// each project specific override
+    pub fn from_sources<S: AsRef<str>, P: AsRef<str>>(sources: &[S], project_sources: &[P])
+                                                      -> EBResult<Datamodel> {
         let mut node_types = HashMap::new();
-        let resolver = &try!(Resolver::new());
+        let resolver = &try!(Resolver::new(sources));
 
-        for schema in SCHEMAS.iter() {
+        for schema in sources {
             let yaml = try!(load_yaml(schema.as_ref()));
             let id = try!(yaml_str(&yaml, "id"));
 
@@ -240,13 +381,90 @@ impl Datamodel {
                 continue
             }
 
-            let resolved = try!(resolver.resolve("root", &yaml));
+            let resolved = try!(resolver.resolve(id, &yaml));
             let node_type = try!(resolved.node_type());
 
             debug!("Loaded schema {}", node_type.label);
             node_types.insert(node_type.label.clone(), node_type);
         }
 
-        Ok(Datamodel { node_types: node_types })
+        let mut project_node_types = HashMap::new();
+        for schema in project_sources {
+            let yaml = try!(load_yaml(schema.as_ref()));
+            let id = try!(yaml_str(&yaml, "id"));
+            let program = try!(yaml_str(&yaml, "program"));
+            let project = try!(yaml_str(&yaml, "project"));
+            let project_id = format!("{}-{}", program, project);
+
+            let base = try!(node_types.get(id).ok_or(
+                format!("{} overrides unknown node type {}", project_id, id)));
+            let resolved = try!(resolver.resolve(id, &yaml));
+            let node_type = try!(resolved.overlay(base));
+
+            debug!("Loaded {} overrides for {}", project_id, id);
+            project_node_types.entry(project_id).or_insert_with(HashMap::new).insert(id.to_string(), node_type);
+        }
+
+        Ok(Datamodel { node_types: node_types, project_node_types: project_node_types })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ::types::*;
+
+    const THING: &'static str = r##"
+id: thing
+category: biospecimen
+required: [size, cases]
+links:
+  - {name: cases, backref: things, label: derived_from, target_type: case, multiplicity: many_to_one}
+properties:
+  size: {type: integer}
+  colour: {type: [string, "null"], enum: [red, green, blue]}
+  notes: {type: string}
+  cases: {type: object}
+"##;
+
+    const BRCA_THING: &'static str = r##"
+id: thing
+program: TCGA
+project: BRCA
+required: [size, colour]
+properties:
+  colour: {enum: [red]}
+  notes: {description: Free text}
+  weight: {type: number}
+  cases: {type: string}
+"##;
+
+    #[test]
+    fn overrides_apply_to_their_project() {
+        let datamodel = Datamodel::from_sources(&[THING], &[BRCA_THING]).unwrap();
+        let base = datamodel.node_type_for_project("thing", Some("TCGA-LUAD")).unwrap();
+        let brca = datamodel.node_type_for_project("thing", Some("TCGA-BRCA")).unwrap();
+
+        assert_eq!(base.props["colour"].enum_values(), Some(vec!["red".into(), "green".into(), "blue".into()]));
+        assert!(base.props.get("weight").is_none());
+        assert_eq!(base.required, vec!["size".to_string(), "cases".to_string()]);
+
+        // Enums are tightened without losing null, and descriptions
+        // don't change the type
+        assert_eq!(brca.props["colour"], PropertyType::Nullable(Box::new(PropertyType::Enum(vec!["red".into()]))));
+        assert_eq!(brca.props["notes"], PropertyType::String);
+        assert_eq!(brca.props["weight"], PropertyType::Decimal);
+        assert_eq!(brca.props["size"], base.props["size"]);
+        assert_eq!(brca.required, vec!["size".to_string(), "colour".to_string()]);
+
+        // Links aren't properties, even when overridden
+        assert!(brca.props.get("cases").is_none());
+        assert_eq!(brca.links.len(), 1);
+    }
+
+    #[test]
+    fn overrides_of_unknown_types_are_errors() {
+        let other = BRCA_THING.replace("id: thing", "id: widget");
+        assert!(Datamodel::from_sources(&[THING], &[other]).is_err());
     }
 }
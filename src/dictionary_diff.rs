@@ -0,0 +1,207 @@
+//! Compares two versions of the dictionary.
+//!
+//! Every node type, property, enum value and link that was added,
+//! removed or changed between two Datamodels is reported, and each
+//! change is flagged as breaking if existing index mappings or
+//! documents can no longer be used as is.  Additions and enum changes
+//! are never breaking; removals, property type changes that change
+//! the Elasticsearch field type, and links that change target or
+//! correlation are.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use ::types::*;
+
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Change {
+    NodeTypeAdded(String),
+    NodeTypeRemoved(String),
+    PropertyAdded { label: String, property: String, prop_type: PropertyType },
+    PropertyRemoved { label: String, property: String },
+    PropertyRetyped { label: String, property: String, old: PropertyType, new: PropertyType },
+    EnumValuesAdded { label: String, property: String, values: Vec<String> },
+    EnumValuesRemoved { label: String, property: String, values: Vec<String> },
+    LinkAdded { label: String, name: String, dst_label: String },
+    LinkRemoved { label: String, name: String, dst_label: String },
+    LinkChanged { label: String, name: String, old: String, new: String, breaking: bool },
+}
+
+
+#[derive(Debug)]
+pub struct DictionaryDiff {
+    pub changes: Vec<Change>,
+}
+
+
+impl Change {
+    /// Whether the change requires rebuilding the index mappings
+    pub fn is_breaking(&self) -> bool {
+        match *self {
+            Change::NodeTypeRemoved(_) => true,
+            Change::PropertyRemoved { .. } => true,
+            Change::PropertyRetyped { ref old, ref new, .. } => old.es_type() != new.es_type(),
+            Change::LinkRemoved { .. } => true,
+            Change::LinkChanged { breaking, .. } => breaking,
+            _ => false,
+        }
+    }
+}
+
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Change::NodeTypeAdded(ref label) =>
+                write!(f, "{}: node type added", label),
+            Change::NodeTypeRemoved(ref label) =>
+                write!(f, "{}: node type removed", label),
+            Change::PropertyAdded { ref label, ref property, ref prop_type } =>
+                write!(f, "{}.{}: property added ({:?})", label, property, prop_type),
+            Change::PropertyRemoved { ref label, ref property } =>
+                write!(f, "{}.{}: property removed", label, property),
+            Change::PropertyRetyped { ref label, ref property, ref old, ref new } =>
+                write!(f, "{}.{}: retyped from {:?} to {:?}", label, property, old, new),
+            Change::EnumValuesAdded { ref label, ref property, ref values } =>
+                write!(f, "{}.{}: enum values added {:?}", label, property, values),
+            Change::EnumValuesRemoved { ref label, ref property, ref values } =>
+                write!(f, "{}.{}: enum values removed {:?}", label, property, values),
+            Change::LinkAdded { ref label, ref name, ref dst_label } =>
+                write!(f, "{}.{}: link to {} added", label, name, dst_label),
+            Change::LinkRemoved { ref label, ref name, ref dst_label } =>
+                write!(f, "{}.{}: link to {} removed", label, name, dst_label),
+            Change::LinkChanged { ref label, ref name, ref old, ref new, .. } =>
+                write!(f, "{}.{}: link changed from {} to {}", label, name, old, new),
+        }
+    }
+}
+
+
+impl DictionaryDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn breaking_changes(&self) -> Vec<&Change> {
+        self.changes.iter().filter(|change| change.is_breaking()).collect()
+    }
+}
+
+
+impl fmt::Display for DictionaryDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for change in &self.changes {
+            let flag = if change.is_breaking() { "BREAKING" } else { "" };
+            try!(writeln!(f, "{:<9}{}", flag, change));
+        }
+        writeln!(f, "{} changes, {} breaking", self.changes.len(), self.breaking_changes().len())
+    }
+}
+
+
+/// Describes a link for comparison, e.g. `case (many_to_one, member_of)`
+fn describe_link(link: &EdgeType) -> String {
+    format!("{} ({:?}, {})", link.dst_label, link.multiplicity, link.label)
+}
+
+
+/// The type without its enum values, so that changing them isn't
+/// reported as a retype
+fn shape(prop_type: &PropertyType) -> PropertyType {
+    match *prop_type {
+        PropertyType::Enum(_) => PropertyType::Enum(Vec::new()),
+        PropertyType::Nullable(ref inner) => PropertyType::Nullable(Box::new(shape(inner))),
+        PropertyType::Array(ref inner) => PropertyType::Array(Box::new(shape(inner))),
+        PropertyType::Union(ref types) => PropertyType::Union(types.iter().map(shape).collect()),
+        ref other => other.clone(),
+    }
+}
+
+
+fn diff_properties(label: &str, old: &NodeType, new: &NodeType, changes: &mut Vec<Change>) {
+    let properties = old.props.keys().chain(new.props.keys()).collect::<BTreeSet<_>>();
+    for property in properties {
+        let (label, property) = (label.to_string(), property.clone());
+        let (old_type, new_type) = match (old.props.get(&property), new.props.get(&property)) {
+            (None, Some(prop_type)) => {
+                changes.push(Change::PropertyAdded { label: label, property: property, prop_type: prop_type.clone() });
+                continue
+            },
+            (Some(_), None) => {
+                changes.push(Change::PropertyRemoved { label: label, property: property });
+                continue
+            },
+            (Some(old_type), Some(new_type)) => (old_type, new_type),
+            (None, None) => continue,
+        };
+
+        if shape(old_type) != shape(new_type) {
+            changes.push(Change::PropertyRetyped {
+                label: label.clone(), property: property.clone(), old: old_type.clone(), new: new_type.clone()
+            });
+        }
+
+        let (old_values, new_values) = match (old_type.enum_values(), new_type.enum_values()) {
+            (Some(old_values), Some(new_values)) => (old_values, new_values),
+            _ => continue,
+        };
+        let added = new_values.iter().filter(|v| !old_values.contains(v)).cloned().collect::<Vec<_>>();
+        let removed = old_values.iter().filter(|v| !new_values.contains(v)).cloned().collect::<Vec<_>>();
+        if !added.is_empty() {
+            changes.push(Change::EnumValuesAdded { label: label.clone(), property: property.clone(), values: added });
+        }
+        if !removed.is_empty() {
+            changes.push(Change::EnumValuesRemoved { label: label, property: property, values: removed });
+        }
+    }
+}
+
+
+fn diff_links(label: &str, old: &NodeType, new: &NodeType, changes: &mut Vec<Change>) {
+    let names = old.links.iter().chain(new.links.iter()).map(|link| &link.name).collect::<BTreeSet<_>>();
+    for name in names {
+        let old_link = old.links.iter().find(|link| &link.name == name);
+        let new_link = new.links.iter().find(|link| &link.name == name);
+        let (label, name) = (label.to_string(), name.clone());
+        match (old_link, new_link) {
+            (None, Some(link)) =>
+                changes.push(Change::LinkAdded { label: label, name: name, dst_label: link.dst_label.clone() }),
+            (Some(link), None) =>
+                changes.push(Change::LinkRemoved { label: label, name: name, dst_label: link.dst_label.clone() }),
+            (Some(old_link), Some(new_link)) => {
+                let (old_desc, new_desc) = (describe_link(old_link), describe_link(new_link));
+                if old_desc != new_desc {
+                    // Documents nest objects for to one links and lists for to many
+                    let breaking = old_link.dst_label != new_link.dst_label
+                        || old_link.correlation() != new_link.correlation();
+                    changes.push(Change::LinkChanged {
+                        label: label, name: name, old: old_desc, new: new_desc, breaking: breaking
+                    });
+                }
+            },
+            (None, None) => {},
+        }
+    }
+}
+
+
+/// Returns every change from the `old` dictionary to the `new` one
+pub fn diff(old: &Datamodel, new: &Datamodel) -> DictionaryDiff {
+    let mut changes = Vec::new();
+    let labels = old.node_types.keys().chain(new.node_types.keys()).collect::<BTreeSet<_>>();
+
+    for label in labels {
+        match (old.node_types.get(label), new.node_types.get(label)) {
+            (None, Some(_)) => changes.push(Change::NodeTypeAdded(label.clone())),
+            (Some(_), None) => changes.push(Change::NodeTypeRemoved(label.clone())),
+            (Some(old_type), Some(new_type)) => {
+                diff_properties(label, old_type, new_type, &mut changes);
+                diff_links(label, old_type, new_type, &mut changes);
+            },
+            (None, None) => {},
+        }
+    }
+
+    DictionaryDiff { changes: changes }
+}
@@ -0,0 +1,97 @@
+//! Renders the dictionary as a GraphViz DOT diagram.
+//!
+//! Node types are colored by category and links are labeled with
+//! their name and multiplicity.  Given a TypeTree, the node types and
+//! links it walks are highlighted so that anything the documents
+//! don't cover stands out, e.g.
+//!
+//! ```text
+//! esbuild dot --highlight case | dot -Tsvg > dictionary.svg
+//! ```
+
+use std::collections::HashSet;
+
+use ::types::*;
+
+
+impl NodeCategory {
+    /// The fill color of node types in this category
+    pub fn color(&self) -> &'static str {
+        match *self {
+            NodeCategory::DataFile => "#a6cee3",
+            NodeCategory::Biospecimen => "#b2df8a",
+            NodeCategory::Notation => "#fdbf6f",
+            NodeCategory::Administrative => "#cab2d6",
+            NodeCategory::Analysis => "#fb9a99",
+            NodeCategory::Clinical => "#ffff99",
+            NodeCategory::IndexFile => "#1f78b4",
+            NodeCategory::MetadataFile => "#33a02c",
+            NodeCategory::Other => "#dddddd",
+        }
+    }
+}
+
+
+/// Quotes a DOT ID, escaping quotes and backslashes
+fn quote(id: &str) -> String {
+    format!("\"{}\"", id.replace("\\", "\\\\").replace("\"", "\\\""))
+}
+
+
+/// Collects the labels and (parent, child) label pairs of a TypeTree
+fn tree_coverage(tree: &TypeTree, labels: &mut HashSet<String>, pairs: &mut HashSet<(String, String)>) {
+    labels.insert(tree.label.clone());
+    for child in &tree.children {
+        pairs.insert((tree.label.clone(), child.label.clone()));
+        tree_coverage(child, labels, pairs);
+    }
+}
+
+
+/// Returns the dictionary as a DOT digraph, highlighting the node
+/// types and links covered by `highlight` if given
+pub fn datamodel_to_dot(datamodel: &Datamodel, highlight: Option<&TypeTree>) -> String {
+    let mut labels = HashSet::new();
+    let mut pairs = HashSet::new();
+    if let Some(tree) = highlight {
+        tree_coverage(tree, &mut labels, &mut pairs);
+    }
+
+    let mut node_types = datamodel.node_types.values().collect::<Vec<_>>();
+    node_types.sort_by(|a, b| a.label.cmp(&b.label));
+
+    let mut dot = String::from("digraph dictionary {\n");
+    dot.push_str("    rankdir=LR;\n");
+    dot.push_str("    node [shape=box, style=filled, fontname=Helvetica];\n");
+    dot.push_str("    edge [fontname=Helvetica, fontsize=10];\n\n");
+
+    for node_type in &node_types {
+        let style = match highlight.is_some() && labels.contains(&node_type.label) {
+            true => ", penwidth=3",
+            false => "",
+        };
+        dot.push_str(&format!("    {} [fillcolor=\"{}\"{}];\n",
+                              quote(&node_type.label), node_type.category.color(), style));
+    }
+    dot.push_str("\n");
+
+    for node_type in &node_types {
+        let mut links = node_type.links.iter().collect::<Vec<_>>();
+        links.sort_by(|a, b| a.name.cmp(&b.name));
+        for link in links {
+            // Trees walk links in either direction
+            let covered = pairs.contains(&(link.src_label.clone(), link.dst_label.clone()))
+                || pairs.contains(&(link.dst_label.clone(), link.src_label.clone()));
+            let style = match covered {
+                true => ", color=red, penwidth=2",
+                false => "",
+            };
+            let label = format!("{} ({})", link.name, link.multiplicity.as_str());
+            dot.push_str(&format!("    {} -> {} [label={}{}];\n",
+                                  quote(&link.src_label), quote(&link.dst_label), quote(&label), style));
+        }
+    }
+
+    dot.push_str("}\n");
+    dot
+}
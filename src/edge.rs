@@ -1,6 +1,6 @@
+use ::naming::edge_tablename;
+use ::table_names::EDGE_TABLES;
 use ::types::*;
-use crypto::digest::Digest;
-use crypto::md5::Md5;
 use std::fmt;
 use std::str;
 
@@ -12,11 +12,6 @@ impl fmt::Display for Edge {
 }
 
 
-fn prefix(s: String, k: usize) -> String {
-    let idx = s.char_indices().nth(k).map(|(idx, _)| idx).unwrap_or(s.len());
-    s[0..idx].to_string()
-}
-
 impl Edge {
     pub fn new<S>(label: S, src_id: S, dst_id: S) -> Edge
         where S: Into<String>
@@ -31,50 +26,102 @@ impl Edge {
 
 
 impl EdgeType {
-    /// Generate a name for the edge table.
-    ///
-    /// Because of the limit on table name length on PostgreSQL, we have
-    /// to truncate some of the longer names.  To do this we concatenate
-    /// the first 2 characters of each word in each of the input arguments
-    /// up to 10 characters (per argument).  However, this strategy would
-    /// very likely lead to collisions in naming.  Therefore, we take the
-    /// first 8 characters of a hash of the full, un-truncated name
-    /// *before* we truncate and prepend this to the truncation.  This
-    /// gets us a name like ``edge_721d393f_LaLeSeqDaFrLaLeSeBu``.  This
-    /// is rather an undesirable workaround. - jsm
+    /// How many destination nodes a source node can have over this link
+    pub fn correlation(&self) -> Correlation
+    {
+        match self.multiplicity {
+            Multiplicity::OneToOne | Multiplicity::ManyToOne => Correlation::ToOne,
+            Multiplicity::OneToMany | Multiplicity::ManyToMany => Correlation::ToMany,
+        }
+    }
+
+    /// The name of the edge table.  Names of the links in the
+    /// embedded dictionary are generated by build.rs, including any
+    /// overrides in `table_names.yaml`, others follow the psqlgraph
+    /// rules in `naming`.
     pub fn get_tablename(&self) -> String
     {
+        let generated = EDGE_TABLES.iter().find(|&&(src_label, label, dst_label, _)| {
+            src_label == self.src_label && label == self.label && dst_label == self.dst_label
+        });
+        match generated {
+            Some(&(_, _, _, tablename)) => tablename.to_string(),
+            None => edge_tablename(&self.src_label, &self.label, &self.dst_label),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ::naming::{edge_table, edge_tablename, node_table};
+    use ::table_names::{EDGE_TABLES, NODE_TABLES};
+    use ::types::*;
+    use yaml_rust::{Yaml, YamlLoader};
+
+    /// Tables as psqlgraph names them, both short and hashed
+    const PSQLGRAPH_EDGE_TABLES: [(&'static str, &'static str, &'static str, &'static str); 4] = [
+        ("case", "member_of", "project", "edge_casememberofproject"),
+        ("file", "data_from", "aliquot", "edge_filedatafromaliquot"),
+        ("sample", "derived_from", "case", "edge_samplederivedfromcase"),
+        ("submitted_aligned_reads", "data_from", "read_group", "edge_fcc48b51_sualredafrregr"),
+    ];
 
-        let tablename = format!(
-            "edge_{}{}{}",
-            self.src_label.replace("_", ""),
-            self.label.replace("_", ""),
-            self.dst_label.replace("_", ""),
-        );
-
-        // If the name is too long, prepend it with the first 8 hex of it"s hash
-        // truncate the each part of the name
-        if tablename.len() > 40 {
-            let mut digest = [0; 16];
-            let mut hasher = Md5::new();
-            hasher.input(tablename.as_bytes());
-            hasher.result(&mut digest);
-
-            let part1 = prefix(self.src_label.split("_")
-                .map(|s| s[..2].to_string()).collect::<Vec<_>>().join(""), 10);
-            let part2 = &*prefix(self.label.split("_")
-                .map(|s| s[..2].to_string()).collect::<Vec<_>>().join(""), 10);
-            let part3 = prefix(self.dst_label.split("_")
-                .map(|s| s[..2].to_string()).collect::<Vec<_>>().join(""), 10);
-
-            let hash = digest[..4].iter()
-                .map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join("");
-            let suffix = format!("{}{}{}", part1, part2, part3);
-
-            format!("edge_{}_{}", hash, suffix)
-
-        } else {
-            tablename
+    fn overrides() -> Yaml {
+        YamlLoader::load_from_str(include_str!("table_names.yaml")).unwrap().pop().unwrap_or(Yaml::Null)
+    }
+
+    #[test]
+    fn generated_node_tables_follow_psqlgraph() {
+        let overrides = overrides();
+        for &(label, tablename) in NODE_TABLES.iter() {
+            let expected = overrides["nodes"][label].as_str().map(String::from)
+                .unwrap_or_else(|| format!("node_{}", label.replace("_", "")));
+            assert_eq!(tablename, expected, "table of {}", label);
+        }
+    }
+
+    #[test]
+    fn edge_tables_follow_psqlgraph() {
+        for &(src_label, label, dst_label, tablename) in PSQLGRAPH_EDGE_TABLES.iter() {
+            assert_eq!(edge_tablename(src_label, label, dst_label), tablename);
+            let generated = EDGE_TABLES.iter()
+                .find(|&&(src, lbl, dst, _)| (src, lbl, dst) == (src_label, label, dst_label))
+                .map(|&(_, _, _, tablename)| tablename);
+            assert_eq!(generated, Some(tablename), "table of {}:{}:{}", src_label, label, dst_label);
         }
     }
+
+    #[test]
+    fn short_words_are_kept_whole() {
+        assert_eq!(edge_tablename("a_b_submitted_aligned_reads", "data_from", "x_read_group"),
+                   "edge_b957fd08_absualredafrxregr");
+    }
+
+    #[test]
+    fn overrides_take_precedence() {
+        let overrides = YamlLoader::load_from_str(
+            "nodes: {case: node_legacycase}\nedges: {\"case:member_of:project\": edge_legacy}"
+        ).unwrap().pop().unwrap();
+        assert_eq!(node_table(&overrides, "case"), "node_legacycase");
+        assert_eq!(node_table(&overrides, "sample"), "node_sample");
+        assert_eq!(edge_table(&overrides, "case", "member_of", "project"), "edge_legacy");
+        assert_eq!(edge_table(&overrides, "sample", "derived_from", "case"), "edge_samplederivedfromcase");
+    }
+
+    #[test]
+    fn links_outside_the_dictionary_follow_psqlgraph() {
+        let link = EdgeType {
+            name: "things".into(),
+            label: "made_of".into(),
+            backref: "parts".into(),
+            src_label: "unknown_long_node_type".into(),
+            dst_label: "another_unknown_type".into(),
+            multiplicity: Multiplicity::ManyToMany,
+            required: false,
+            subgroup_exclusive: false,
+            subgroup_required: false,
+        };
+        assert_eq!(link.get_tablename(), "edge_5adf1978_unlonotymaofanunty");
+    }
 }
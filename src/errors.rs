@@ -5,11 +5,12 @@ use yaml_rust;
 quick_error! {
     #[derive(Debug)]
     pub enum EBError {
-        BuildError(err: String) { from() }
-        ConnectionError(err: postgres::error::ConnectError) { from() }
-        PostgresError(err: postgres::error::Error) { from() }
-        IoError(err: io::Error) { from() }
-        YamlError(err: yaml_rust::ScanError) { from() }
+        BuildError(err: String) { from() display("{}", err) }
+        ConnectionError(err: postgres::error::ConnectError) { from() display("Unable to connect to postgres: {}", err) }
+        PostgresError(err: postgres::error::Error) { from() display("Postgres error: {}", err) }
+        IoError(err: io::Error) { from() display("{}", err) }
+        YamlError(err: yaml_rust::ScanError) { from() display("Invalid YAML: {}", err) }
+        ConfigError(path: String, message: String) { display("{}: {}", path, message) }
         Error(message: &'static str) { description(message) display("Error: {}", message) from() }
     }
 }
@@ -0,0 +1,110 @@
+//! Serializes the resolved dictionary to JSON.
+//!
+//! The output mirrors the dictionary API: one JSON schema per node
+//! type, keyed by label, with all `$ref`s already inlined.  Since it
+//! is produced from the Datamodel rather than the YAML, it shows
+//! exactly the properties, types and links that esbuild indexes.
+//! Project specific overrides are listed under `_projects`.
+
+use serde_json::Value;
+
+use ::types::*;
+
+
+impl PropertyType {
+    /// The JSON schema of values of this type
+    pub fn json_schema(&self) -> Doc {
+        match *self {
+            PropertyType::Integer => doc!({"type"; "integer"}),
+            PropertyType::Decimal => doc!({"type"; "number"}),
+            PropertyType::String => doc!({"type"; "string"}),
+            PropertyType::Boolean => doc!({"type"; "boolean"}),
+            PropertyType::Datetime => doc!({"type"; "string", "format"; "date-time"}),
+            PropertyType::Enum(ref values) => doc!({"enum"; values}),
+            PropertyType::Array(ref inner) => doc!({"type"; "array", "items"; inner.json_schema()}),
+            PropertyType::Object => doc!({"type"; "object"}),
+            PropertyType::Nullable(ref inner) => {
+                let mut schema = inner.json_schema();
+                let name = match schema.get("type") {
+                    Some(&Value::String(ref name)) if schema.len() == 1 => Some(name.clone()),
+                    _ => None,
+                };
+                match name {
+                    Some(name) => setitem!(schema, "type".to_string(), vec![name, "null".to_string()]),
+                    None if schema.len() == 1 && schema.contains_key("oneOf") =>
+                        append!(schema, "oneOf".to_string(), doc!({"type"; "null"})),
+                    None => return doc!({"oneOf"; vec![schema, doc!({"type"; "null"})]}),
+                }
+                schema
+            },
+            PropertyType::Union(ref types) => {
+                let alternatives = types.iter().map(|t| t.json_schema()).collect::<Vec<_>>();
+                doc!({"oneOf"; alternatives})
+            },
+        }
+    }
+}
+
+
+impl EdgeType {
+    pub fn to_json(&self) -> Doc {
+        doc!({
+            "name"; self.name,
+            "backref"; self.backref,
+            "label"; self.label,
+            "target_type"; self.dst_label,
+            "multiplicity"; self.multiplicity.as_str(),
+            "required"; self.required,
+            "subgroup_exclusive"; self.subgroup_exclusive,
+            "subgroup_required"; self.subgroup_required
+        })
+    }
+}
+
+
+impl NodeType {
+    /// The resolved schema of the node type
+    pub fn to_json(&self) -> Doc {
+        let mut properties = Doc::new();
+        for (key, prop_type) in &self.props {
+            setitem!(properties, key, prop_type.json_schema());
+        }
+
+        let links = self.links.iter().map(|link| link.to_json()).collect::<Vec<_>>();
+        let backrefs = self.backrefs.iter().map(|link| link.to_json()).collect::<Vec<_>>();
+
+        doc!({
+            "id"; self.label,
+            "category"; self.category.as_str(),
+            "properties"; properties,
+            "required"; self.required,
+            "uniqueKeys"; self.unique_keys,
+            "systemProperties"; self.system_properties,
+            "links"; links,
+            "backrefs"; backrefs
+        })
+    }
+}
+
+
+impl Datamodel {
+    /// The resolved dictionary, keyed by label
+    pub fn to_json(&self) -> Doc {
+        let mut dictionary = Doc::new();
+        for (label, node_type) in &self.node_types {
+            setitem!(dictionary, label, node_type.to_json());
+        }
+
+        let mut projects = Doc::new();
+        for (project_id, node_types) in &self.project_node_types {
+            let mut overrides = Doc::new();
+            for (label, node_type) in node_types {
+                setitem!(overrides, label, node_type.to_json());
+            }
+            setitem!(projects, project_id, overrides);
+        }
+        setitem!(dictionary, "_projects".to_string(), projects);
+
+        dictionary
+    }
+}
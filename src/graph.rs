@@ -1,18 +1,23 @@
 use openssl;
-use postgres::error::ConnectError;
 use postgres::{Connection, SslMode};
-use serde_json::Value;
-use std::collections::HashMap;
-use std::fmt::Display;
+use serde_json::{self, Value};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Write};
 
+use ::config::{PostgresConfig, PostgresSslMode};
 use ::types::*;
 use ::errors::*;
 
 
 #[derive(Debug)]
 pub struct CachedGraph {
-    pub graph: HashMap<String, HashMap<String, Edge>>,
-    pub nodes: HashMap<String, Node>
+    /// The edges of each node, by the node at their other end.  Two
+    /// nodes can have several edges between them with different labels
+    pub graph: HashMap<String, HashMap<String, Vec<Edge>>>,
+    pub nodes: HashMap<String, Node>,
+    pub supplements: HashMap<String, String>,
+    pub index_files: HashMap<String, Vec<String>>,
+    pub index_parents: HashMap<String, String>,
 }
 
 
@@ -39,7 +44,10 @@ impl CachedGraph {
     {
         CachedGraph {
             nodes: HashMap::new(),
-            graph: HashMap::new()
+            graph: HashMap::new(),
+            supplements: HashMap::new(),
+            index_files: HashMap::new(),
+            index_parents: HashMap::new(),
         }
     }
 
@@ -61,9 +69,19 @@ impl CachedGraph {
                 self.graph.insert(edge.dst_id.clone(), HashMap::new());
             }
 
-            // Add the edge in both directions
-            self.graph.get_mut(&edge.src_id).unwrap().insert(edge.dst_id.clone(), edge.clone());
-            self.graph.get_mut(&edge.dst_id).unwrap().insert(edge.src_id.clone(), edge);
+            // Add the edge in both directions, once
+            {
+                let edges = self.graph.get_mut(&edge.src_id).unwrap()
+                    .entry(edge.dst_id.clone()).or_insert_with(Vec::new);
+                if edges.contains(&edge) {
+                    return Ok(())
+                }
+                edges.push(edge.clone());
+            }
+            if edge.src_id != edge.dst_id {
+                self.graph.get_mut(&edge.dst_id).unwrap()
+                    .entry(edge.src_id.clone()).or_insert_with(Vec::new).push(edge);
+            }
 
             Ok(())
         }
@@ -105,9 +123,64 @@ impl CachedGraph {
         }
     }
 
-    pub fn get_edge<'a>(&'a self, src_id: &String, dst_id: &String) -> Option<&'a Edge>
+    /// Returns the nodes this node points to through edges with the given label
+    pub fn outgoing_labeled<'a>(&'a self, id: &String, edge_label: &str) -> Vec<&'a Node>
+    {
+        match self.graph.get(id) {
+            Some(map) => {
+                map.iter()
+                    .filter(|&(_, edges)| edges.iter().any(|edge| &edge.src_id == id && edge.label == edge_label))
+                    .map(|(dst, _)| self.get_node(dst).unwrap())
+                    .collect()
+            },
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the nodes this node points to, regardless of edge label
+    pub fn outgoing<'a>(&'a self, id: &String) -> Vec<&'a Node>
+    {
+        match self.graph.get(id) {
+            Some(map) => {
+                map.iter()
+                    .filter(|&(_, edges)| edges.iter().any(|edge| &edge.src_id == id))
+                    .map(|(dst, _)| self.get_node(dst).unwrap())
+                    .collect()
+            },
+            None => Vec::new(),
+        }
+    }
+
+    /// Follows each path of labels out from the given node and returns
+    /// the distinct nodes found at the end of the paths
+    pub fn nodes_along_paths<'a>(&'a self, id: &String, paths: &Vec<Vec<String>>) -> Vec<&'a Node>
+    {
+        let mut found = HashSet::new();
+        let mut nodes = Vec::new();
+        for path in paths {
+            let mut frontier = vec![id.clone()];
+            for label in path.iter().skip(1) {
+                let mut next = frontier.iter()
+                    .flat_map(|id| self.neighbors_labeled(id, label))
+                    .map(|node| node.id.clone())
+                    .collect::<Vec<_>>();
+                next.sort();
+                next.dedup();
+                frontier = next;
+            }
+            for id in frontier {
+                if found.insert(id.clone()) {
+                    nodes.push(self.get_node(&id).unwrap());
+                }
+            }
+        }
+        nodes
+    }
+
+    /// The edges between two nodes, in either direction
+    pub fn get_edges<'a>(&'a self, src_id: &String, dst_id: &String) -> &'a [Edge]
     {
-        self.graph.get(src_id).map_or(None, |r| r.get(dst_id))
+        self.graph.get(src_id).and_then(|r| r.get(dst_id)).map_or(&[], |edges| &edges[..])
     }
 
     pub fn add_node(&mut self, node: Node)
@@ -115,42 +188,318 @@ impl CachedGraph {
         self.nodes.insert(node.id.clone(), node);
     }
 
+    /// Every edge once, from its source
+    pub fn edges<'a>(&'a self) -> Vec<&'a Edge>
+    {
+        self.graph.iter()
+            .flat_map(|(id, neighbors)| {
+                neighbors.values().flat_map(|edges| edges.iter()).filter(move |edge| &edge.src_id == id)
+            })
+            .collect()
+    }
+
+    /// Writes the graph as JSON lines, first every node sorted by id
+    /// and then every edge sorted by source, destination and label
+    pub fn dump<W: Write>(&self, writer: &mut W) -> EBResult<()>
+    {
+        let mut nodes = self.nodes.values().collect::<Vec<_>>();
+        nodes.sort_by(|a, b| a.id.cmp(&b.id));
+        for node in nodes {
+            let doc = doc!({
+                "type"; "node", "id"; node.id, "label"; node.label,
+                "props"; node.props, "sysan"; node.sysan, "acl"; node.acl
+            });
+            try!(writeln!(writer, "{}", serde_json::to_string(&doc).unwrap()));
+        }
+
+        let mut edges = self.edges();
+        edges.sort_by(|a, b| (&a.src_id, &a.dst_id, &a.label).cmp(&(&b.src_id, &b.dst_id, &b.label)));
+        for edge in edges {
+            let doc = doc!({"type"; "edge", "label"; edge.label, "src_id"; edge.src_id, "dst_id"; edge.dst_id});
+            try!(writeln!(writer, "{}", serde_json::to_string(&doc).unwrap()));
+        }
+        Ok(())
+    }
+
+    /// Returns the supplement category the file was classified as, if any
+    pub fn supplement_type<'a>(&'a self, id: &String) -> Option<&'a String>
+    {
+        self.supplements.get(id)
+    }
+
+    /// Tags every file whose file_name matches one of the supplement
+    /// regexes with the category of the first regex it matched
+    pub fn classify_supplements(&mut self, options: &CachingOptions)
+    {
+        let files = self.nodes.values().filter(|node| options.file_labels.contains(&node.label));
+        for file in files {
+            let file_name = match file_name(file) {
+                Some(file_name) => file_name,
+                None => continue,
+            };
+            let category = options.supplement_regexes.iter()
+                .find(|&&(_, ref regex)| regex.is_match(file_name))
+                .map(|&(ref category, _)| category.clone());
+            if let Some(category) = category {
+                self.supplements.insert(file.id.clone(), category);
+            }
+        }
+    }
+
+    /// Returns the index files attached to the given file
+    pub fn index_files_of<'a>(&'a self, id: &String) -> Vec<&'a Node>
+    {
+        match self.index_files.get(id) {
+            Some(ids) => ids.iter().filter_map(|index_id| self.get_node(index_id)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns whether the node is an index file attached to a data file
+    pub fn is_index_file(&self, id: &String) -> bool
+    {
+        self.index_parents.contains_key(id)
+    }
+
+    /// Matches each index file (e.g. `.bai`, `.tbi`) to the data file
+    /// it indexes.  A `related_to` edge to a non-index file takes
+    /// precedence, otherwise the file name with the index extension
+    /// stripped (`x.bam.bai` -> `x.bam`, or `x.bai` -> `x.*`) is used.
+    /// Index files whose name matches several data files are left
+    /// unattached.
+    pub fn attach_index_files(&mut self, options: &CachingOptions)
+    {
+        let mut parents = Vec::new();
+        {
+            let files = self.nodes.values()
+                .filter(|node| options.file_labels.contains(&node.label))
+                .collect::<Vec<_>>();
+
+            let mut data_files = HashSet::new();
+            let mut by_name = HashMap::new();
+            let mut by_stem = HashMap::new();
+            for file in files.iter().filter(|file| index_extension(options, file).is_none()) {
+                data_files.insert(&file.id);
+                if let Some(name) = file_name(file) {
+                    by_name.entry(name).or_insert_with(Vec::new).push(&file.id);
+                    if let Some(dot) = name.rfind('.') {
+                        by_stem.entry(&name[..dot]).or_insert_with(Vec::new).push(&file.id);
+                    }
+                }
+            }
+
+            for index in &files {
+                let extension = match index_extension(options, index) {
+                    Some(extension) => extension,
+                    None => continue,
+                };
+
+                // The lowest id, should it be related to several
+                let related = self.graph.get(&index.id).and_then(|edges| {
+                    edges.values()
+                        .flat_map(|edges| edges.iter())
+                        .filter(|edge| edge.label == "related_to")
+                        .map(|edge| if edge.src_id == index.id { &edge.dst_id } else { &edge.src_id })
+                        .filter(|id| data_files.contains(id))
+                        .min()
+                });
+
+                let stem = file_name(index).map(|name| &name[..name.len() - extension.len()]);
+                let candidates = related.map(|id| vec![id])
+                    .or_else(|| stem.and_then(|stem| by_name.get(stem).cloned()))
+                    .or_else(|| stem.and_then(|stem| by_stem.get(stem).cloned()));
+
+                let parent = match candidates {
+                    Some(ref ids) if ids.len() > 1 => {
+                        let mut ids = ids.iter().map(|id| &***id).collect::<Vec<&str>>();
+                        ids.sort();
+                        warn!("Index file {} matches data files {}, not attaching it", index, ids.join(", "));
+                        continue
+                    },
+                    Some(ids) => ids.first().cloned(),
+                    None => None,
+                };
+
+                match parent {
+                    Some(parent) => parents.push((index.id.clone(), parent.clone())),
+                    None => debug!("No data file found for index file {}", index),
+                }
+            }
+        }
+
+        for (index_id, parent_id) in parents {
+            self.index_files.entry(parent_id.clone()).or_insert_with(Vec::new).push(index_id.clone());
+            self.index_parents.insert(index_id, parent_id);
+        }
+    }
+
     /// Loads all Node and Edge tables defined in the datamodel using the
     /// given Postgres connection
     #[allow(unused_variables)]
     pub fn from_postgres(options: &CachingOptions, datamodel: &Datamodel, connection: &Connection)
                          -> EBResult<CachedGraph>
     {
-        let mut graph = CachedGraph::new();
-
+        let mut nodes = Vec::new();
         for (_, node_type) in &datamodel.node_types {
-            let nodes: Vec<Node> = try!(load_node_table(node_type, &connection));
-            for node in nodes {
-                graph.add_node(node);
-            }
+            nodes.extend(try!(load_node_table(node_type, &connection)));
         }
 
+        let mut edges = Vec::new();
         for (_, node_type) in &datamodel.node_types {
             for link in &node_type.links {
-                let edges = try!(load_edge_table(link, &connection));
-                for edge in edges {
-                    try!(graph.add_edge(edge))
-                }
+                edges.extend(try!(load_edge_table(link, &connection)));
+            }
+        }
+
+        info!("Loaded {} nodes from postgres", nodes.len());
+        CachedGraph::cache(options, nodes, edges)
+    }
+
+    /// Loads a graph written by `dump`
+    pub fn from_dump<R: BufRead>(options: &CachingOptions, reader: R) -> EBResult<CachedGraph>
+    {
+        let mut nodes = Vec::new();
+        let mut edges = Vec::new();
+
+        for (number, line) in reader.lines().enumerate() {
+            let line = try!(line);
+            if line.trim().is_empty() {
+                continue
+            }
+            let doc: Doc = try!(serde_json::from_str(&*line)
+                                .map_err(|err| format!("Line {}: {}", number + 1, err)));
+            let field = |key: &str| doc.get(key).and_then(|value| value.as_str()).map(String::from)
+                .ok_or(format!("Line {}: missing {}", number + 1, key));
+
+            match doc.get("type").and_then(|value| value.as_str()) {
+                Some("node") => {
+                    let object = |key: &str| doc.get(key).and_then(|value| value.as_object()).cloned()
+                        .unwrap_or_else(Doc::new);
+                    let acl = doc.get("acl").and_then(|value| value.as_array()).map_or(Vec::new(), |acl| {
+                        acl.iter().filter_map(|value| value.as_str()).map(String::from).collect()
+                    });
+                    nodes.push(Node::new(try!(field("label")), try!(field("id")),
+                                         object("props"), object("sysan"), acl));
+                },
+                // Nodes come first in a dump, but don't rely on it
+                Some("edge") => edges.push(Edge::new(try!(field("label")), try!(field("src_id")), try!(field("dst_id")))),
+                _ => return Err(format!("Line {}: type must be node or edge", number + 1).into()),
+            }
+        }
+
+        info!("Loaded {} nodes from dump", nodes.len());
+        CachedGraph::cache(options, nodes, edges)
+    }
+
+    /// Builds the graph from the loaded nodes and edges, leaving out
+    /// those the caching options exclude
+    fn cache(options: &CachingOptions, nodes: Vec<Node>, edges: Vec<Edge>) -> EBResult<CachedGraph>
+    {
+        let mut graph = CachedGraph::new();
+        let mut excluded = HashSet::new();
+        for node in nodes {
+            match is_excluded(options, &node) {
+                true => { excluded.insert(node.id); },
+                false => graph.add_node(node),
             }
         }
 
-        info!("Loaded {} nodes from postgres", graph.nodes.len());
+        let mut undifferentiated = 0;
+        for edge in edges {
+            if excluded.contains(&edge.src_id) || excluded.contains(&edge.dst_id) {
+                continue
+            }
+            if try!(graph.is_undifferentiated(options, &edge)) {
+                undifferentiated += 1;
+                continue
+            }
+            try!(graph.add_edge(edge))
+        }
+
+        graph.classify_supplements(options);
+        graph.attach_index_files(options);
+
+        info!("Excluded {} nodes and {} undifferentiated edges", excluded.len(), undifferentiated);
+        info!("Cached {} nodes", graph.nodes.len());
+        info!("Classified {} supplement files", graph.supplements.len());
+        info!("Attached {} index files", graph.index_parents.len());
         Ok(graph)
     }
+
+    /// Whether the edge is left out because `differentiated_edges`
+    /// names another edge label between the same node types, which
+    /// is then the only one followed between them
+    fn is_undifferentiated(&self, options: &CachingOptions, edge: &Edge) -> EBResult<bool>
+    {
+        let src_label = &try!(self.nodes.get(&edge.src_id).ok_or(format!("Source id {} not in graph", edge.src_id))).label;
+        let dst_label = &try!(self.nodes.get(&edge.dst_id).ok_or(format!("Destination id {} not in graph", edge.dst_id))).label;
+        let mut labels = options.differentiated_edges.iter()
+            .filter(|&&(ref src, _, ref dst)| src == src_label && dst == dst_label)
+            .map(|&(_, ref label, _)| label)
+            .peekable();
+        Ok(labels.peek().is_some() && !labels.any(|label| label == &edge.label))
+    }
+
+    /// The table an edge is stored in, from the labels of its nodes
+    pub fn edge_tablename(&self, datamodel: &Datamodel, edge: &Edge) -> String
+    {
+        let src_label = self.nodes.get(&edge.src_id).map_or("", |node| &*node.label);
+        let dst_label = self.nodes.get(&edge.dst_id).map_or("", |node| &*node.label);
+        let link = datamodel.node_types.get(src_label).and_then(|node_type| {
+            node_type.links.iter().find(|link| link.label == edge.label && link.dst_label == dst_label)
+        });
+        match link {
+            Some(link) => link.get_tablename(),
+            None => format!("{}:{}:{}", src_label, edge.label, dst_label),
+        }
+    }
 }
 
+/// Whether the caching options leave the node out of the graph: it's
+/// in an omitted project, redacted (unless its label is one of those
+/// kept when redacted), or matches one of its label's
+/// `unindexed_by_property` documents
+fn is_excluded(options: &CachingOptions, node: &Node) -> bool
+{
+    if node.project_id().map_or(false, |id| options.omitted_projects.iter().any(|project| project == id)) {
+        return true
+    }
+    let redacted = node.sysan.get("_redacted").and_then(|value| value.as_bool()).unwrap_or(false);
+    if redacted && !options.redacted_but_not_suppressed.contains(&node.label) {
+        return true
+    }
+    options.unindexed_by_property.get(&node.label).map_or(false, |matches| {
+        matches.iter().any(|doc| doc.iter().all(|(key, value)| node.props.get(key) == Some(value)))
+    })
+}
+
+
+fn file_name(node: &Node) -> Option<&str>
+{
+    node.props.get("file_name").and_then(|name| name.as_str())
+}
+
+
+/// Returns the index file extension the node's file_name ends with, if any
+fn index_extension<'a>(options: &'a CachingOptions, node: &Node) -> Option<&'a String>
+{
+    file_name(node).and_then(|name| {
+        options.index_file_extensions.iter().find(|extension| name.ends_with(&**extension))
+    })
+}
+
+
 /// Returns a connection to Postgres if able to connect
-pub fn connect<S>(host: S, database: S, user: S, pass: S) -> Result<Connection, ConnectError>
-    where S: Display
+pub fn connect(config: &PostgresConfig) -> EBResult<Connection>
 {
-    let conn_str = format!("postgres://{}:{}@{}/{}", user, pass, host, database);
+    let params = try!(config.connect_params());
     let ctx = openssl::ssl::SslContext::new(openssl::ssl::SslMethod::Sslv23).unwrap();
-    Connection::connect(&*conn_str,  SslMode::Prefer(&ctx))
+    let ssl_mode = match try!(config.ssl_mode()) {
+        PostgresSslMode::Disable => SslMode::None,
+        PostgresSslMode::Prefer => SslMode::Prefer(&ctx),
+        PostgresSslMode::Require => SslMode::Require(&ctx),
+    };
+    Ok(try!(Connection::connect(params, ssl_mode)))
 }
 
 
@@ -0,0 +1,182 @@
+//! Compares two versions of the graph.
+//!
+//! Nodes are matched by id and edges by their source, destination
+//! and label.  Added and removed nodes are reported with their label,
+//! changed nodes with each property whose value differs, and added
+//! and removed edges with the table they're stored in, so that a
+//! submission can be checked against what it was meant to change.
+
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use ::graph::CachedGraph;
+use ::types::*;
+
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct PropertyChange {
+    pub property: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum GraphChange {
+    NodeAdded { label: String, id: String },
+    NodeRemoved { label: String, id: String },
+    NodeChanged { label: String, id: String, properties: Vec<PropertyChange> },
+    EdgeAdded { table: String, edge: Edge },
+    EdgeRemoved { table: String, edge: Edge },
+}
+
+
+#[derive(Debug)]
+pub struct GraphDiff {
+    pub changes: Vec<GraphChange>,
+}
+
+
+impl fmt::Display for GraphChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GraphChange::NodeAdded { ref label, ref id } =>
+                write!(f, "{} {}: node added", label, id),
+            GraphChange::NodeRemoved { ref label, ref id } =>
+                write!(f, "{} {}: node removed", label, id),
+            GraphChange::NodeChanged { ref label, ref id, ref properties } => {
+                try!(write!(f, "{} {}: node changed", label, id));
+                for change in properties {
+                    try!(write!(f, "\n    {}: {} -> {}", change.property, change.old, change.new));
+                }
+                Ok(())
+            },
+            GraphChange::EdgeAdded { ref table, ref edge } =>
+                write!(f, "{} {}: edge added", table, edge),
+            GraphChange::EdgeRemoved { ref table, ref edge } =>
+                write!(f, "{} {}: edge removed", table, edge),
+        }
+    }
+}
+
+
+impl GraphDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Numbers of added, removed and changed nodes per label
+    pub fn node_counts(&self) -> BTreeMap<&str, (usize, usize, usize)> {
+        let mut counts = BTreeMap::new();
+        for change in &self.changes {
+            match *change {
+                GraphChange::NodeAdded { ref label, .. } => counts.entry(&**label).or_insert((0, 0, 0)).0 += 1,
+                GraphChange::NodeRemoved { ref label, .. } => counts.entry(&**label).or_insert((0, 0, 0)).1 += 1,
+                GraphChange::NodeChanged { ref label, .. } => counts.entry(&**label).or_insert((0, 0, 0)).2 += 1,
+                _ => {},
+            }
+        }
+        counts
+    }
+
+    /// Numbers of added and removed edges per table
+    pub fn edge_counts(&self) -> BTreeMap<&str, (usize, usize)> {
+        let mut counts = BTreeMap::new();
+        for change in &self.changes {
+            match *change {
+                GraphChange::EdgeAdded { ref table, .. } => counts.entry(&**table).or_insert((0, 0)).0 += 1,
+                GraphChange::EdgeRemoved { ref table, .. } => counts.entry(&**table).or_insert((0, 0)).1 += 1,
+                _ => {},
+            }
+        }
+        counts
+    }
+}
+
+
+impl fmt::Display for GraphDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for change in &self.changes {
+            try!(writeln!(f, "{}", change));
+        }
+        if !self.changes.is_empty() {
+            try!(writeln!(f, ""));
+        }
+        for (label, (added, removed, changed)) in self.node_counts() {
+            try!(writeln!(f, "{}: {} added, {} removed, {} changed", label, added, removed, changed));
+        }
+        for (table, (added, removed)) in self.edge_counts() {
+            try!(writeln!(f, "{}: {} added, {} removed", table, added, removed));
+        }
+        writeln!(f, "{} changes", self.changes.len())
+    }
+}
+
+
+fn diff_properties(old: &Node, new: &Node) -> Vec<PropertyChange> {
+    let properties = old.props.keys().chain(new.props.keys()).collect::<BTreeSet<_>>();
+    properties.into_iter().filter_map(|property| {
+        let old_value = old.props.get(property).cloned().unwrap_or(Value::Null);
+        let new_value = new.props.get(property).cloned().unwrap_or(Value::Null);
+        match old_value == new_value {
+            true => None,
+            false => Some(PropertyChange { property: property.clone(), old: old_value, new: new_value }),
+        }
+    }).collect()
+}
+
+
+/// Edges keyed by source, destination and label
+fn edges_by_key(graph: &CachedGraph) -> BTreeMap<(&str, &str, &str), &Edge> {
+    graph.edges().into_iter()
+        .map(|edge| ((&*edge.src_id, &*edge.dst_id, &*edge.label), edge))
+        .collect()
+}
+
+
+/// Returns every change from the `old` graph to the `new` one.  The
+/// datamodel names the tables of the edges
+pub fn diff(datamodel: &Datamodel, old: &CachedGraph, new: &CachedGraph) -> GraphDiff {
+    let mut changes = Vec::new();
+
+    let ids = old.nodes.keys().chain(new.nodes.keys()).collect::<BTreeSet<_>>();
+    for id in ids {
+        match (old.nodes.get(id), new.nodes.get(id)) {
+            (None, Some(node)) =>
+                changes.push(GraphChange::NodeAdded { label: node.label.clone(), id: id.clone() }),
+            (Some(node), None) =>
+                changes.push(GraphChange::NodeRemoved { label: node.label.clone(), id: id.clone() }),
+            // A node that changed label has moved tables, like a new node
+            (Some(old_node), Some(new_node)) if old_node.label != new_node.label => {
+                changes.push(GraphChange::NodeRemoved { label: old_node.label.clone(), id: id.clone() });
+                changes.push(GraphChange::NodeAdded { label: new_node.label.clone(), id: id.clone() });
+            },
+            (Some(old_node), Some(new_node)) => {
+                let properties = diff_properties(old_node, new_node);
+                if !properties.is_empty() {
+                    changes.push(GraphChange::NodeChanged {
+                        label: new_node.label.clone(), id: id.clone(), properties: properties
+                    });
+                }
+            },
+            (None, None) => {},
+        }
+    }
+
+    let (old_edges, new_edges) = (edges_by_key(old), edges_by_key(new));
+    let keys = old_edges.keys().chain(new_edges.keys()).collect::<BTreeSet<_>>();
+    for key in keys {
+        match (old_edges.get(key), new_edges.get(key)) {
+            (None, Some(&edge)) => changes.push(GraphChange::EdgeAdded {
+                table: new.edge_tablename(datamodel, edge), edge: edge.clone()
+            }),
+            (Some(&edge), None) => changes.push(GraphChange::EdgeRemoved {
+                table: old.edge_tablename(datamodel, edge), edge: edge.clone()
+            }),
+            _ => {},
+        }
+    }
+
+    GraphDiff { changes: changes }
+}
@@ -1,14 +1,52 @@
 use ::types::*;
+use regex::Regex;
+
+
+fn legacy_file_labels() -> Vec<String> {
+    vec!["file".into()]
+}
+
+
+/// Biospecimen entities (and cases) that files are associated with
+fn legacy_associated_entities() -> Vec<String> {
+    vec![
+        "aliquot".into(), "analyte".into(), "portion".into(),
+        "sample".into(), "slide".into(), "case".into(),
+    ]
+}
+
 
 impl Options {
     pub fn legacy_defaults(datamodel: Datamodel) -> Options {
+        let file_labels = legacy_file_labels();
+        let entities = legacy_associated_entities();
         Options {
+            case_to_file_paths: datamodel.label_paths("case", &file_labels, &entities),
             datamodel: datamodel,
-            case_to_file_paths: Vec::new(),
-            file_labels: Vec::new(),
-            possible_associated_entites: Vec::new(),
-            index_file_extensions: Vec::new(),
+            file_labels: file_labels,
+            possible_associated_entites: entities,
+            index_file_extensions: vec![".bai".into(), ".tbi".into()],
             index_type: IndexType::Legacy,
+            deterministic: false,
         }
     }
 }
+
+
+impl CachingOptions {
+    pub fn legacy_defaults(datamodel: &Datamodel) -> CachingOptions {
+        let mut options = CachingOptions::new();
+        options.file_labels = legacy_file_labels();
+        options.possible_associated_entites = legacy_associated_entities();
+        options.case_to_file_paths = datamodel.label_paths(
+            "case", &options.file_labels, &options.possible_associated_entites);
+        options.index_file_extensions = vec![".bai".into(), ".tbi".into()];
+        options.supplement_regexes = vec![
+            ("biospecimen_supplement".into(),
+             Regex::new(r"(?i).*(biospecimen|control|auxiliary).*\.xml$").unwrap()),
+            ("clinical_supplement".into(),
+             Regex::new(r"(?i).*(clinical|omf|follow_up).*\.xml$").unwrap()),
+        ];
+        options
+    }
+}
@@ -61,6 +61,7 @@ extern crate threadpool;
 extern crate walkdir;
 extern crate yaml_rust;
 extern crate crypto;
+extern crate flate2;
 
 #[macro_use]
 pub mod macros;
@@ -70,8 +71,23 @@ pub mod node;
 pub mod edge;
 pub mod types;
 pub mod datamodel;
+pub mod schema;
 pub mod dictionary;
+pub mod conversion;
+pub mod models;
+pub mod naming;
+pub mod table_names;
+pub mod selection;
+pub mod validation;
+pub mod mapping;
+pub mod dictionary_diff;
+pub mod build_diff;
+pub mod graph_diff;
+pub mod dot;
+pub mod export;
+pub mod output;
 
+pub mod config;
 pub mod common;
 pub mod legacy;
-// pub mod active;
+pub mod active;
@@ -21,6 +21,19 @@ macro_rules! setitem {
     };
 }
 
+/// Append a value to a list in a Doc, creating the list if needed
+macro_rules! append {
+    ( $original:expr, $key:expr, $val:expr ) => {
+        {
+            use serde_json::{to_value, Value};
+            let list = $original.entry($key.clone()).or_insert(Value::Array(Vec::new()));
+            if let Value::Array(ref mut items) = *list {
+                items.push(to_value(&$val));
+            }
+        }
+    };
+}
+
 /// Create a Doc
 macro_rules! doc {
     ( { $( $key:expr ; $val:expr ),* } ) => {
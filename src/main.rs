@@ -3,6 +3,7 @@
 
 #[macro_use]
 extern crate log;
+extern crate clap;
 extern crate esbuild;
 extern crate openssl;
 extern crate postgres;
@@ -12,83 +13,662 @@ extern crate env_logger;
 extern crate threadpool;
 extern crate scoped_pool;
 
-use esbuild::common::{case_type_tree, denormalize_tree};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use esbuild::build_diff;
+use esbuild::common::{case_type_tree, file_type_tree, denormalize_case};
+use esbuild::config::{port_number, Config, PostgresSslMode};
+use esbuild::dictionary_diff;
+use esbuild::dot::datamodel_to_dot;
 use esbuild::errors::EBResult;
 use esbuild::graph::{connect, CachedGraph};
-use esbuild::types::{Datamodel, CachingOptions, Options, NodeTree};
+use esbuild::graph_diff;
+use esbuild::mapping::case_mapping;
+use esbuild::output::ShardedWriter;
+use esbuild::selection::random_seed;
+use esbuild::types::{Doc, Datamodel, CachingOptions, CaseSelection, Correlation, IndexType, Options, TypeTree};
+use esbuild::validation::validate;
 use postgres::Connection;
 use scoped_pool::Pool;
-use std::env;
-use std::sync::mpsc::channel;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write, BufReader, BufWriter};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::thread;
 
 
-/// Pull in environment variables to create postgres connection
-fn env_psql() -> EBResult<Connection> {
-    let host = env::var("PG_HOST").unwrap_or("localhost".to_string());
-    let database = env::var("PG_DATABASE").unwrap_or("automated_test".to_string());
-    let user = env::var("PG_USER").unwrap_or(env::var("USER").unwrap_or("postgres".to_string()));
-    let password = env::var("PG_PASSWORD").unwrap_or("".to_string());
+/// Create a postgres connection from the configuration
+fn config_psql(config: &Config) -> EBResult<Connection> {
+    connect(&config.postgres)
+}
+
+
+/// Opens the configured output, defaulting to stdout
+fn open_output(config: &Config) -> EBResult<Box<Write + Send>> {
+    Ok(match config.output {
+        Some(ref path) => Box::new(BufWriter::new(try!(File::create(path)))),
+        None => Box::new(BufWriter::new(io::stdout())),
+    })
+}
+
+
+/// The caching options for the index type, with the configured overrides
+fn caching_options(config: &Config, datamodel: &Datamodel, index_type: IndexType) -> EBResult<CachingOptions> {
+    let mut caching_options = match index_type {
+        IndexType::Legacy => CachingOptions::legacy_defaults(datamodel),
+        IndexType::Active => CachingOptions::active_defaults(datamodel),
+    };
+    try!(config.apply_caching_options(&mut caching_options, datamodel));
+    Ok(caching_options)
+}
 
-    Ok(try!(connect(host, database, user, password)))
+
+/// The denormalization options for the index type, with the configured overrides
+fn options(config: &Config, datamodel: Datamodel, index_type: IndexType) -> EBResult<Options> {
+    let mut options = match index_type {
+        IndexType::Legacy => Options::legacy_defaults(datamodel),
+        IndexType::Active => Options::active_defaults(datamodel),
+    };
+    try!(config.apply_options(&mut options));
+    // The command line takes precedence over the configuration
+    options.index_type = index_type;
+    Ok(options)
+}
+
+
+/// Cache the graph from postgres
+fn cache_graph(config: &Config, datamodel: &Datamodel, index_type: IndexType) -> EBResult<CachedGraph> {
+    let connection = try!(config_psql(config));
+    let caching_options = try!(caching_options(config, datamodel, index_type));
+    CachedGraph::from_postgres(&caching_options, datamodel, &connection)
 }
 
 
-fn denormalize(graph: &CachedGraph, options: &Options) ->EBResult<()> {
+fn denormalize(graph: &CachedGraph, options: &Options, config: &Config, selection: &CaseSelection)
+               -> EBResult<()> {
     // Setup denormalization
-    let cases = graph.nodes_labeled("case");
-    let case_type_tree = &case_type_tree();
-    let n_cases = cases.len();
-    let pool = Pool::new(16);
+    let cases = selection.select(graph);
+    let case_type_tree = &try!(config.type_tree("case", case_type_tree).correlate(&options.datamodel));
+    let file_type_tree = &try!(config.type_tree("file", file_type_tree).correlate(&options.datamodel));
+    let pool = Pool::new(config.threads);
+
+    // Write documents as they're produced.  The channel is bounded so
+    // that workers wait for the writer instead of piling up documents
+    let (tx, rx) = sync_channel::<Doc>(config.queue_size);
+    let writer = match config.shards.is_enabled() {
+        true => {
+            let path = try!(config.output.clone().ok_or("Sharded output needs an output file"));
+            let mut output = ShardedWriter::new(path, config.shards.clone());
+            thread::spawn(move || -> EBResult<usize> {
+                for case_doc in rx {
+                    try!(output.write_line(&*serde_json::to_string(&case_doc).unwrap()));
+                }
+                let shards = try!(output.finish());
+                info!("Wrote {} shards", shards.len());
+                Ok(shards.iter().map(|shard| shard.documents).sum())
+            })
+        },
+        false => {
+            let mut output = try!(open_output(config));
+            thread::spawn(move || -> EBResult<usize> {
+                let mut written = 0;
+                for case_doc in rx {
+                    try!(writeln!(output, "{}", serde_json::to_string(&case_doc).unwrap()));
+                    try!(output.flush());
+                    written += 1;
+                }
+                Ok(written)
+            })
+        },
+    };
 
     // Do the denormalization
-    let (tx, rx) = channel();
-    pool.scoped(|scope| {
-        for case in cases {
-            let tx = tx.clone();
-            scope.execute(move || {
-                debug!("Denormalizing {:}", case);
-                let case_tree = &NodeTree::construct(graph, case_type_tree, case);
-                let doc = denormalize_tree(options, graph, case_tree);
-                tx.send(doc).unwrap();
+    let build = |case| {
+        debug!("Denormalizing {:}", case);
+        denormalize_case(options, graph, case_type_tree, file_type_tree, case)
+    };
+    match options.deterministic {
+        // Cases are selected in order of id.  Build queue_size of them
+        // at a time and send them in that order, so that no more
+        // documents are held than the channel would hold
+        true => for chunk in cases.chunks(config.queue_size) {
+            let mut docs = vec![None; chunk.len()];
+            pool.scoped(|scope| {
+                for (&case, doc) in chunk.iter().zip(docs.iter_mut()) {
+                    scope.execute(move || *doc = Some(build(case)))
+                }
+            });
+            // The writer only hangs up on errors, which it reports
+            if docs.into_iter().map(|doc| tx.send(doc.unwrap())).any(|sent| sent.is_err()) {
+                break
+            }
+        },
+        // Otherwise documents are written as they finish.  Once the
+        // writer hangs up the remaining cases are skipped
+        false => {
+            let stopped = &AtomicBool::new(false);
+            pool.scoped(|scope| {
+                for case in cases {
+                    let tx = tx.clone();
+                    scope.execute(move || {
+                        if stopped.load(Ordering::Relaxed) {
+                            return
+                        }
+                        if tx.send(build(case)).is_err() {
+                            stopped.store(true, Ordering::Relaxed);
+                        }
+                    })
+                }
             })
-        }
-    });
+        },
+    }
+    drop(tx);
+
+    let written = try!(writer.join().unwrap_or(Err("Writer thread panicked".into())));
+    info!("Wrote {} cases", written);
+    Ok(())
+}
 
-    debug!("Collecting cases");
-    let case_docs = rx.iter().take(n_cases).collect::<Vec<_>>();
-    for case_doc in case_docs {
-        println!("{:?}", case_doc);
+
+/// Loads the dictionary from the given directory, or the one
+/// included at build time
+fn load_datamodel(dictionary: Option<&str>) -> EBResult<Datamodel> {
+    match dictionary {
+        Some(dir) => {
+            info!("Loading dictionary from {}", dir);
+            Datamodel::from_dir(dir)
+        },
+        None => Datamodel::new(),
     }
+}
+
 
+/// Build the index documents of the selected cases
+fn build_index(config: &Config, datamodel: Datamodel, index_type: IndexType, selection: &CaseSelection,
+               deterministic: bool) -> EBResult<()> {
+    let graph = &try!(cache_graph(config, &datamodel, index_type));
+    let mut options = try!(options(config, datamodel, index_type));
+    options.deterministic = options.deterministic || deterministic;
+    denormalize(graph, &options, config, selection)
+}
+
+
+/// The cases selected on the command line, every case by default
+fn case_selection(matches: &ArgMatches) -> EBResult<CaseSelection> {
+    let values = |name| matches.values_of(name)
+        .map_or(Vec::new(), |values| values.map(String::from).collect());
+
+    let mut selection = CaseSelection::all();
+    selection.case_ids = values("case");
+    selection.submitter_ids = values("submitter-id");
+    selection.projects = values("project");
+    if let Some(sample) = matches.value_of("sample") {
+        selection.sample = Some(try!(sample.parse()
+            .map_err(|_| format!("--sample must be a number, got {}", sample))));
+    }
+    selection.seed = match matches.value_of("seed") {
+        Some(seed) => try!(seed.parse().map_err(|_| format!("--seed must be a number, got {}", seed))),
+        None => random_seed(),
+    };
+    Ok(selection)
+}
+
+
+/// Check every node in the graph against the dictionary, failing if
+/// any problems are found
+fn validate_graph(config: &Config, datamodel: Datamodel, index_type: IndexType) -> EBResult<()> {
+    let graph = try!(cache_graph(config, &datamodel, index_type));
+    let report = validate(&datamodel, &graph);
+
+    let mut output = try!(open_output(config));
+    try!(write!(output, "{}", report));
+    match report.is_valid() {
+        true => Ok(()),
+        false => Err(format!("Found {} problems", report.problem_count()).into()),
+    }
+}
+
+
+/// Write the Elasticsearch mapping of the case documents
+fn write_mapping(config: &Config, datamodel: Datamodel, index_type: IndexType) -> EBResult<()> {
+    let caching_options = try!(caching_options(config, &datamodel, index_type));
+    let options = try!(options(config, datamodel, index_type));
+    let case_tree = try!(config.type_tree("case", case_type_tree).correlate(&options.datamodel));
+    let file_tree = try!(config.type_tree("file", file_type_tree).correlate(&options.datamodel));
+
+    let mut mappings = serde_json::Map::new();
+    let mapping = case_mapping(&options, &caching_options, &case_tree, &file_tree);
+    mappings.insert("case".to_string(), serde_json::to_value(&mapping));
+
+    let mut output = try!(open_output(config));
+    try!(writeln!(output, "{}", serde_json::to_string_pretty(&mappings).unwrap()));
     Ok(())
 }
 
 
-/// Build the legacy index
-fn build_legacy_index() -> EBResult<()> {
-    // Construct datamode from included resources
-    let mut datamodel = try!(Datamodel::new());
+/// Write the cached graph as JSON lines
+fn dump_graph(config: &Config, datamodel: Datamodel, index_type: IndexType) -> EBResult<()> {
+    let graph = try!(cache_graph(config, &datamodel, index_type));
+    let mut output = try!(open_output(config));
+    graph.dump(&mut output)
+}
+
 
-    // Cache the graph
-    let connection = try!(env_psql());
-    let caching_options = &CachingOptions::new();
-    let graph = &try!(CachedGraph::from_postgres(caching_options, &datamodel, &connection));
-    let options = &Options::legacy_defaults(datamodel);
+/// Load a graph written by dump-graph
+fn load_graph_dump(config: &Config, datamodel: &Datamodel, index_type: IndexType, path: &str)
+                   -> EBResult<CachedGraph> {
+    let caching_options = try!(caching_options(config, datamodel, index_type));
+    let file = try!(File::open(path).map_err(|err| format!("{}: {}", path, err)));
+    CachedGraph::from_dump(&caching_options, BufReader::new(file))
+        .map_err(|err| format!("{}: {}", path, err).into())
+}
 
-    try!(denormalize(graph, options));
 
+/// Compare a graph dump to another one, or to the graph in postgres
+fn diff_graph(config: &Config, datamodel: Datamodel, index_type: IndexType, old_path: &str, new_path: Option<&str>)
+              -> EBResult<()> {
+    let old = try!(load_graph_dump(config, &datamodel, index_type, old_path));
+    let new = match new_path {
+        Some(new_path) => try!(load_graph_dump(config, &datamodel, index_type, new_path)),
+        None => try!(cache_graph(config, &datamodel, index_type)),
+    };
+    let mut output = try!(open_output(config));
+    try!(write!(output, "{}", graph_diff::diff(&datamodel, &old, &new)));
     Ok(())
 }
 
 
-fn main() {
-    env_logger::init().unwrap();
+/// Report how many of each node and edge the graph has
+fn graph_stats(config: &Config, datamodel: Datamodel, index_type: IndexType) -> EBResult<()> {
+    let graph = try!(cache_graph(config, &datamodel, index_type));
+
+    let mut nodes = BTreeMap::new();
+    for node in graph.nodes.values() {
+        *nodes.entry(&node.label).or_insert(0) += 1;
+    }
+    let mut edges = BTreeMap::new();
+    for edge in graph.edges() {
+        let src_label = &graph.nodes[&edge.src_id].label;
+        let dst_label = &graph.nodes[&edge.dst_id].label;
+        *edges.entry((src_label, &edge.label, dst_label)).or_insert(0) += 1;
+    }
 
-    if let Err(error) = Datamodel::new() {
-        println!("{:?}", error)
+    let mut output = try!(open_output(config));
+    try!(writeln!(output, "{} nodes", graph.nodes.len()));
+    for (label, count) in nodes {
+        try!(writeln!(output, "  {:>10} {}", count, label));
     }
+    try!(writeln!(output, "{} edges", edges.values().sum::<usize>()));
+    for ((src_label, label, dst_label), count) in edges {
+        try!(writeln!(output, "  {:>10} {} -{}-> {}", count, src_label, label, dst_label));
+    }
+    try!(writeln!(output, "{} supplement files", graph.supplements.len()));
+    try!(writeln!(output, "{} index files", graph.index_parents.len()));
+    Ok(())
+}
+
+
+/// Write a type tree, one node per line
+fn write_tree(output: &mut Write, tree: &TypeTree, depth: usize) -> EBResult<()> {
+    let correlation = match tree.correlation {
+        Correlation::ToOne => "one",
+        Correlation::ToMany => "many",
+    };
+    try!(writeln!(output, "{:indent$}{}: {} (to {})", "", tree.title, tree.label, correlation, indent=depth * 2));
+    for child in &tree.children {
+        try!(write_tree(output, child, depth + 1));
+    }
+    Ok(())
+}
+
+
+/// Describe how documents are built, without connecting to postgres
+fn explain(config: &Config, datamodel: Datamodel, index_type: IndexType) -> EBResult<()> {
+    let caching_options = try!(caching_options(config, &datamodel, index_type));
+    let options = try!(options(config, datamodel, index_type));
+    let case_tree = try!(config.type_tree("case", case_type_tree).correlate(&options.datamodel));
+    let file_tree = try!(config.type_tree("file", file_type_tree).correlate(&options.datamodel));
+
+    let mut output = try!(open_output(config));
+    try!(writeln!(output, "Index: {:?}", options.index_type));
+    try!(writeln!(output, "File labels: {}", options.file_labels.join(", ")));
+    try!(writeln!(output, "Associated entities: {}", options.possible_associated_entites.join(", ")));
+    try!(writeln!(output, "Index file extensions: {}", options.index_file_extensions.join(", ")));
+    for &(ref category, ref regex) in &caching_options.supplement_regexes {
+        try!(writeln!(output, "Supplements: {} matching {}", category, regex.as_str()));
+    }
+    if !caching_options.omitted_projects.is_empty() {
+        try!(writeln!(output, "Omitted projects: {}", caching_options.omitted_projects.join(", ")));
+    }
+
+    try!(writeln!(output, "\nCase tree:"));
+    try!(write_tree(&mut output, &case_tree, 1));
+    try!(writeln!(output, "\nFile tree:"));
+    try!(write_tree(&mut output, &file_tree, 1));
+
+    try!(writeln!(output, "\nFiles are found from cases along {} paths:", options.case_to_file_paths.len()));
+    for path in &options.case_to_file_paths {
+        try!(writeln!(output, "  {}", path.join(" -> ")));
+    }
+    Ok(())
+}
+
+
+/// Report the changes from the current dictionary to the one in `new_dir`
+fn diff_dictionary(config: &Config, datamodel: Datamodel, new_dir: &str) -> EBResult<()> {
+    let new = try!(Datamodel::from_dir(new_dir));
+    let mut output = try!(open_output(config));
+    try!(write!(output, "{}", dictionary_diff::diff(&datamodel, &new)));
+    Ok(())
+}
+
+
+/// Compare the documents of two builds
+fn diff_builds(config: &Config, old_path: &str, new_path: &str, id_field: &str) -> EBResult<()> {
+    let diff = try!(build_diff::diff(old_path, new_path, id_field));
+    let mut output = try!(open_output(config));
+    try!(write!(output, "{}", diff));
+    Ok(())
+}
+
+
+/// Write the dictionary as a DOT diagram, highlighting the named type tree
+fn export_dot(config: &Config, datamodel: Datamodel, highlight: Option<&str>) -> EBResult<()> {
+    let tree = match highlight {
+        Some("case") => Some(config.type_tree("case", case_type_tree)),
+        Some("file") => Some(config.type_tree("file", file_type_tree)),
+        Some(name) => Some(try!(config.type_trees.get(name).cloned()
+                                .ok_or(format!("Unknown type tree {}", name)))),
+        None => None,
+    };
+
+    let mut output = try!(open_output(config));
+    try!(write!(output, "{}", datamodel_to_dot(&datamodel, tree.as_ref())));
+    Ok(())
+}
+
+
+/// Write the resolved dictionary as JSON
+fn export_dictionary(config: &Config, datamodel: Datamodel) -> EBResult<()> {
+    let mut output = try!(open_output(config));
+    try!(writeln!(output, "{}", serde_json::to_string_pretty(&datamodel.to_json()).unwrap()));
+    Ok(())
+}
+
+
+fn app() -> App<'static, 'static> {
+    let index = Arg::with_name("index")
+        .short("i")
+        .long("index")
+        .value_name("TYPE")
+        .help("Which index to build the documents for, defaults to the configured one")
+        .possible_values(&["legacy", "active"])
+        .takes_value(true);
+
+    App::new("esbuild")
+        .about("Builds the GDC portal documents from the graph")
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(Arg::with_name("config")
+             .short("c")
+             .long("config")
+             .value_name("FILE")
+             .help("YAML configuration file")
+             .global(true)
+             .takes_value(true))
+        .arg(Arg::with_name("dictionary")
+             .short("d")
+             .long("dictionary")
+             .value_name("DIR")
+             .help("Load the dictionary schemas from DIR instead of the embedded ones")
+             .global(true)
+             .takes_value(true))
+        .arg(Arg::with_name("output")
+             .short("o")
+             .long("output")
+             .value_name("FILE")
+             .help("Write to FILE instead of the configured output, - for stdout")
+             .global(true)
+             .takes_value(true))
+        .arg(Arg::with_name("threads")
+             .short("t")
+             .long("threads")
+             .value_name("N")
+             .help("Number of threads to denormalize with")
+             .global(true)
+             .takes_value(true))
+        .arg(Arg::with_name("url")
+             .long("url")
+             .value_name("URL")
+             .help("Postgres connection URL, which the other Postgres options override parts of")
+             .global(true)
+             .takes_value(true))
+        .arg(Arg::with_name("host")
+             .long("host")
+             .value_name("HOST")
+             .help("Postgres host")
+             .global(true)
+             .takes_value(true))
+        .arg(Arg::with_name("port")
+             .long("port")
+             .value_name("PORT")
+             .help("Postgres port")
+             .global(true)
+             .takes_value(true))
+        .arg(Arg::with_name("database")
+             .long("database")
+             .value_name("NAME")
+             .help("Postgres database")
+             .global(true)
+             .takes_value(true))
+        .arg(Arg::with_name("user")
+             .long("user")
+             .value_name("USER")
+             .help("Postgres user, whose password is taken from PG_PASSWORD or ~/.pgpass")
+             .global(true)
+             .takes_value(true))
+        .arg(Arg::with_name("sslmode")
+             .long("sslmode")
+             .value_name("MODE")
+             .help("Whether to use SSL: disable, prefer or require")
+             .global(true)
+             .takes_value(true))
+        .subcommand(SubCommand::with_name("build")
+                    .about("Builds the index documents (the default)")
+                    .arg(index.clone())
+                    .arg(Arg::with_name("case")
+                         .long("case")
+                         .value_name("ID")
+                         .help("Only build the cases with these ids")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .use_delimiter(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("submitter-id")
+                         .long("submitter-id")
+                         .value_name("ID")
+                         .help("Only build the cases with these submitter ids")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .use_delimiter(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("project")
+                         .long("project")
+                         .value_name("PROJECT_ID")
+                         .help("Only build the cases in these projects, e.g. TCGA-BRCA")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .use_delimiter(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("sample")
+                         .long("sample")
+                         .value_name("N")
+                         .help("Only build N of the cases, picked at random with --seed")
+                         .takes_value(true))
+                    .arg(Arg::with_name("seed")
+                         .long("seed")
+                         .value_name("SEED")
+                         .help("Seed for picking the sample, so that it can be reproduced. Defaults to one \
+                                from the time, which is logged")
+                         .requires("sample")
+                         .takes_value(true))
+                    .arg(Arg::with_name("shard-documents")
+                         .long("shard-documents")
+                         .value_name("N")
+                         .help("Split the output into files of at most N documents")
+                         .takes_value(true))
+                    .arg(Arg::with_name("shard-bytes")
+                         .long("shard-bytes")
+                         .value_name("BYTES")
+                         .help("Split the output into files of at most BYTES (uncompressed) bytes")
+                         .takes_value(true))
+                    .arg(Arg::with_name("gzip")
+                         .long("gzip")
+                         .help("Gzip the output files"))
+                    .arg(Arg::with_name("deterministic")
+                         .long("deterministic")
+                         .help("Write documents and their lists in order of id, so that builds can be diffed")))
+        .subcommand(SubCommand::with_name("validate")
+                    .about("Validates the graph against the dictionary")
+                    .arg(index.clone()))
+        .subcommand(SubCommand::with_name("mapping")
+                    .about("Writes the Elasticsearch mapping of the documents")
+                    .arg(index.clone()))
+        .subcommand(SubCommand::with_name("dump-graph")
+                    .about("Writes the cached graph as JSON lines")
+                    .arg(index.clone()))
+        .subcommand(SubCommand::with_name("diff-graph")
+                    .about("Compares a graph dump to another one, or to the graph in postgres")
+                    .arg(index.clone())
+                    .arg(Arg::with_name("OLD")
+                         .help("Graph dump to compare from")
+                         .required(true))
+                    .arg(Arg::with_name("NEW")
+                         .help("Graph dump to compare to, postgres if not given")))
+        .subcommand(SubCommand::with_name("stats")
+                    .about("Counts the nodes and edges in the cached graph")
+                    .arg(index.clone()))
+        .subcommand(SubCommand::with_name("explain")
+                    .about("Describes how the documents are built")
+                    .arg(index.clone()))
+        .subcommand(SubCommand::with_name("diff-dictionary")
+                    .about("Compares the dictionary to another version of it")
+                    .arg(Arg::with_name("DIR")
+                         .help("Directory with the other version's schemas")
+                         .required(true)))
+        .subcommand(SubCommand::with_name("diff-builds")
+                    .about("Compares the documents of two builds")
+                    .arg(Arg::with_name("OLD")
+                         .help("Output file or shard manifest of the old build")
+                         .required(true))
+                    .arg(Arg::with_name("NEW")
+                         .help("Output file or shard manifest of the new build")
+                         .required(true))
+                    .arg(Arg::with_name("id-field")
+                         .long("id-field")
+                         .value_name("FIELD")
+                         .help("Field the documents are matched on")
+                         .default_value("case_id")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("dot")
+                    .about("Writes the dictionary as a GraphViz DOT diagram")
+                    .arg(Arg::with_name("highlight")
+                         .long("highlight")
+                         .value_name("TREE")
+                         .help("Highlight the node types covered by the case, file or a configured type tree")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("export-dictionary")
+                    .about("Writes the resolved dictionary as JSON"))
+}
+
+
+/// Loads the configuration and applies the command line overrides
+fn load_config(matches: &ArgMatches) -> EBResult<Config> {
+    let mut config = match matches.value_of("config") {
+        Some(path) => try!(Config::from_file(path)),
+        None => try!(Config::new()),
+    };
+
+    match matches.value_of("output") {
+        Some("-") => config.output = None,
+        Some(path) => config.output = Some(path.to_string()),
+        None => {},
+    }
+    if let Some(threads) = matches.value_of("threads") {
+        config.threads = match threads.parse() {
+            Ok(threads) if threads > 0 => threads,
+            _ => return Err(format!("--threads must be a positive number, got {}", threads).into()),
+        };
+    }
+    if let Some(url) = matches.value_of("url") { config.postgres.set_url(url.to_string()) }
+    if let Some(host) = matches.value_of("host") { config.postgres.host = Some(host.to_string()) }
+    if let Some(port) = matches.value_of("port") {
+        config.postgres.port = Some(try!(port_number(port).map_err(|err| format!("--port: {}", err))));
+    }
+    if let Some(database) = matches.value_of("database") { config.postgres.database = Some(database.to_string()) }
+    if let Some(user) = matches.value_of("user") { config.postgres.user = Some(user.to_string()) }
+    if let Some(sslmode) = matches.value_of("sslmode") {
+        config.postgres.sslmode = Some(try!(PostgresSslMode::parse(sslmode).map_err(|err| format!("--sslmode: {}", err))));
+    }
+
+    if let Some(max_documents) = matches.value_of("shard-documents") {
+        config.shards.max_documents = match max_documents.parse() {
+            Ok(max_documents) if max_documents > 0 => Some(max_documents),
+            _ => return Err(format!("--shard-documents must be a positive number, got {}", max_documents).into()),
+        };
+    }
+    if let Some(max_bytes) = matches.value_of("shard-bytes") {
+        config.shards.max_bytes = match max_bytes.parse() {
+            Ok(max_bytes) if max_bytes > 0 => Some(max_bytes),
+            _ => return Err(format!("--shard-bytes must be a positive number, got {}", max_bytes).into()),
+        };
+    }
+    if matches.is_present("gzip") {
+        config.shards.gzip = true;
+    }
+    Ok(config)
+}
+
+
+fn run(matches: &ArgMatches) -> EBResult<()> {
+    let (command, sub_matches) = matches.subcommand();
+    let sub_matches = sub_matches.unwrap_or(matches);
+    // Global arguments are also available to the subcommand
+    let config = try!(load_config(sub_matches));
+    // Only loaded by the commands that use the dictionary
+    let datamodel = || load_datamodel(sub_matches.value_of("dictionary"));
+
+    let index_type = match sub_matches.value_of("index") {
+        Some(name) => try!(IndexType::parse(name)),
+        None => config.index_type(),
+    };
+
+    match command {
+        "" | "build" => build_index(&config, try!(datamodel()), index_type, &try!(case_selection(sub_matches)),
+                                    sub_matches.is_present("deterministic")),
+        "validate" => validate_graph(&config, try!(datamodel()), index_type),
+        "mapping" => write_mapping(&config, try!(datamodel()), index_type),
+        "dump-graph" => dump_graph(&config, try!(datamodel()), index_type),
+        "diff-graph" => diff_graph(&config, try!(datamodel()), index_type, sub_matches.value_of("OLD").unwrap(),
+                                   sub_matches.value_of("NEW")),
+        "stats" => graph_stats(&config, try!(datamodel()), index_type),
+        "explain" => explain(&config, try!(datamodel()), index_type),
+        "diff-dictionary" => diff_dictionary(&config, try!(datamodel()), sub_matches.value_of("DIR").unwrap()),
+        "diff-builds" => diff_builds(&config, sub_matches.value_of("OLD").unwrap(),
+                                     sub_matches.value_of("NEW").unwrap(), sub_matches.value_of("id-field").unwrap()),
+        "dot" => export_dot(&config, try!(datamodel()), sub_matches.value_of("highlight")),
+        "export-dictionary" => export_dictionary(&config, try!(datamodel())),
+        other => Err(format!("Unknown command {}", other).into()),
+    }
+}
+
+
+fn main() {
+    env_logger::init().unwrap();
 
-    if let Err(error) = build_legacy_index() {
-        println!("{:?}", error)
+    let matches = app().get_matches();
+    if let Err(error) = run(&matches) {
+        writeln!(io::stderr(), "esbuild: {}", error).unwrap();
+        process::exit(1);
     }
 }
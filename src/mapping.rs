@@ -0,0 +1,126 @@
+//! Elasticsearch mappings of dictionary properties and of the
+//! documents built from them.
+
+use serde_json::Value;
+
+use ::types::*;
+
+
+impl PropertyType {
+    /// The Elasticsearch field type values of this type are indexed as
+    pub fn es_type(&self) -> &'static str {
+        match *self {
+            PropertyType::Integer => "long",
+            PropertyType::Decimal => "double",
+            PropertyType::String => "keyword",
+            PropertyType::Boolean => "boolean",
+            PropertyType::Datetime => "date",
+            PropertyType::Enum(_) => "keyword",
+            // Elasticsearch fields hold any number of values
+            PropertyType::Array(ref inner) => inner.es_type(),
+            PropertyType::Object => "object",
+            PropertyType::Nullable(ref inner) => inner.es_type(),
+            PropertyType::Union(ref types) => {
+                let first = types.first().map_or("keyword", |t| t.es_type());
+                match types.iter().all(|t| t.es_type() == first) {
+                    true => first,
+                    // Mixed values can only be indexed as strings
+                    false => "keyword",
+                }
+            },
+        }
+    }
+}
+
+
+/// Adds the mapping of each property of the node types, which share
+/// a field if they're indexed together.  Fields whose types disagree
+/// are indexed as strings
+fn add_properties(options: &Options, labels: &[String], properties: &mut Doc) {
+    for label in labels {
+        let node_type = match options.datamodel.node_types.get(label) {
+            Some(node_type) => node_type,
+            None => continue,
+        };
+        for (key, prop_type) in node_type.props.iter().filter(|&(key, _)| key != "project_id" || label == "project") {
+            let es_type = match properties.get(key).and_then(|mapping| mapping.find("type")) {
+                Some(existing) if existing.as_str() != Some(prop_type.es_type()) => "keyword",
+                _ => prop_type.es_type(),
+            };
+            setitem!(properties, key, doc!({"type"; es_type}));
+        }
+        setitem!(properties, format!("{}_id", label), doc!({"type"; "keyword"}));
+    }
+}
+
+
+/// The mapping of documents built from the type tree.  To many
+/// children are nested so that their properties stay together.  Files
+/// get a `supplement_type` if there are any `supplements` categories
+pub fn tree_mapping(options: &Options, supplements: &[String], tree: &TypeTree) -> Doc {
+    let mut properties = Doc::new();
+    add_properties(options, &[tree.label.clone()], &mut properties);
+    if options.file_labels.contains(&tree.label) {
+        add_file_properties(options, supplements, &mut properties);
+    }
+
+    for child in &tree.children {
+        let mut mapping = tree_mapping(options, supplements, child);
+        if child.correlation == Correlation::ToMany {
+            setitem!(mapping, "type".to_string(), "nested");
+        }
+        setitem!(properties, child.label, mapping);
+    }
+    doc!({"properties"; properties})
+}
+
+
+/// The supplement type, associated entities and index files added to
+/// file documents, see `denormalize_tree`
+fn add_file_properties(options: &Options, supplements: &[String], properties: &mut Doc) {
+    let keyword = doc!({"type"; "keyword"});
+    if !supplements.is_empty() {
+        setitem!(properties, "supplement_type".to_string(), keyword.clone());
+    }
+
+    let entities = doc!({
+        "entity_type"; keyword, "entity_id"; keyword, "entity_submitter_id"; keyword, "case_id"; keyword
+    });
+    setitem!(properties, "associated_entities".to_string(), doc!({"type"; "nested", "properties"; entities}));
+
+    let mut index_files = Doc::new();
+    add_properties(options, &options.file_labels, &mut index_files);
+    setitem!(properties, "index_files".to_string(), doc!({"type"; "nested", "properties"; index_files}));
+}
+
+
+/// The mapping of the files in case documents, which are built with
+/// the file tree but can be of any of the file labels
+fn file_mapping(options: &Options, supplements: &[String], file_tree: &TypeTree) -> Doc {
+    let mut mapping = tree_mapping(options, supplements, file_tree);
+    if let Some(&mut Value::Object(ref mut properties)) = mapping.get_mut("properties") {
+        add_properties(options, &options.file_labels, properties);
+    }
+    setitem!(mapping, "type".to_string(), "nested");
+    mapping
+}
+
+
+/// The mapping of case documents, see `denormalize_case`.  Files and
+/// supplements of each category (e.g. `clinical_supplements`) are
+/// mapped the same way
+pub fn case_mapping(options: &Options, caching_options: &CachingOptions, case_tree: &TypeTree,
+                    file_tree: &TypeTree) -> Doc {
+    let supplements = caching_options.supplement_regexes.iter()
+        .map(|&(ref category, _)| category.clone())
+        .collect::<Vec<_>>();
+    let mut mapping = tree_mapping(options, &supplements, case_tree);
+    let files = file_mapping(options, &supplements, file_tree);
+    if let Some(&mut Value::Object(ref mut properties)) = mapping.get_mut("properties") {
+        for category in &supplements {
+            setitem!(properties, format!("{}s", category), files.clone());
+        }
+        setitem!(properties, file_tree.label, files);
+    }
+    mapping
+}
@@ -0,0 +1,69 @@
+//! The psqlgraph rules for naming node and edge tables.
+//!
+//! build.rs includes this module to generate `table_names.rs`, and the
+//! crate uses it for node types and links that aren't in the embedded
+//! dictionary, so that both always name tables the same way.
+
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use yaml_rust::Yaml;
+
+
+/// The first 2 characters of each word, up to 10 characters.  Words
+/// shorter than 2 characters are kept whole
+pub fn abbreviate(name: &str) -> String {
+    name.split('_')
+        .flat_map(|word| word.chars().take(2))
+        .take(10)
+        .collect()
+}
+
+
+/// The node table name, e.g. `node_alignedreadsindex`
+pub fn node_tablename(label: &str) -> String {
+    format!("node_{}", label.replace("_", ""))
+}
+
+
+/// Generate a name for the edge table.
+///
+/// Because of the limit on table name length on PostgreSQL, we have
+/// to truncate some of the longer names.  To do this we concatenate
+/// the first 2 characters of each word in each of the input arguments
+/// up to 10 characters (per argument).  However, this strategy would
+/// very likely lead to collisions in naming.  Therefore, we take the
+/// first 8 characters of a hash of the full, un-truncated name
+/// *before* we truncate and prepend this to the truncation.  This
+/// gets us a name like ``edge_721d393f_LaLeSeqDaFrLaLeSeBu``.  This
+/// is rather an undesirable workaround. - jsm
+pub fn edge_tablename(src_label: &str, label: &str, dst_label: &str) -> String {
+    let tablename = format!("edge_{}{}{}", src_label.replace("_", ""), label.replace("_", ""),
+                            dst_label.replace("_", ""));
+    if tablename.len() <= 40 {
+        return tablename
+    }
+
+    let mut hasher = Md5::new();
+    hasher.input(tablename.as_bytes());
+    format!("edge_{}_{}{}{}", &hasher.result_str()[..8], abbreviate(src_label), abbreviate(label),
+            abbreviate(dst_label))
+}
+
+
+/// The node table name, unless `table_names.yaml` overrides it
+pub fn node_table(overrides: &Yaml, label: &str) -> String {
+    match overrides["nodes"][label].as_str() {
+        Some(tablename) => tablename.to_string(),
+        None => node_tablename(label),
+    }
+}
+
+
+/// The edge table name, unless `table_names.yaml` overrides it under
+/// `src_label:label:dst_label`
+pub fn edge_table(overrides: &Yaml, src_label: &str, label: &str, dst_label: &str) -> String {
+    match overrides["edges"][&*format!("{}:{}:{}", src_label, label, dst_label)].as_str() {
+        Some(tablename) => tablename.to_string(),
+        None => edge_tablename(src_label, label, dst_label),
+    }
+}
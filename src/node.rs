@@ -1,4 +1,6 @@
 use std::fmt;
+use ::naming::node_tablename;
+use ::table_names::NODE_TABLES;
 use ::types::*;
 use serde_json::Value;
 
@@ -22,14 +24,20 @@ impl Node {
     pub fn get_base_doc_without_id(&self, options: &Options) -> Doc
     {
         let mut doc = Doc::new();
-        options.datamodel.node_types
-            .get(&self.label).unwrap().props.iter()
+        options.datamodel.node_type_for_project(&self.label, self.project_id())
+            .unwrap().props.iter()
             .filter(|&(key, _)| !self.is_prop_hidden(&*key))
             .map(|(key, _)| setitem!(doc, key, *self.props.get(key).unwrap_or(&Value::Null)))
             .collect::<Vec<()>>();
         doc
     }
 
+    /// The project the node belongs to, if it has one
+    pub fn project_id(&self) -> Option<&str>
+    {
+        self.props.get("project_id").and_then(|project_id| project_id.as_str())
+    }
+
     /// Returns a boolean wether the given key should be included in
     /// the base doc for this node
     #[inline(always)]
@@ -65,6 +73,9 @@ impl fmt::Display for Node {
 impl NodeType {
     pub fn get_tablename(&self) -> String
     {
-        format!("node_{}", self.label.replace("_", ""))
+        match NODE_TABLES.iter().find(|&&(label, _)| label == self.label) {
+            Some(&(_, tablename)) => tablename.to_string(),
+            None => node_tablename(&self.label),
+        }
     }
 }
@@ -0,0 +1,429 @@
+//! Writes documents across several, optionally gzipped, files.
+//!
+//! A new shard is started whenever the current one reaches the
+//! document or byte limit, so `/data/cases.json` becomes
+//! `/data/cases.00000.json.gz`, `/data/cases.00001.json.gz`, and so
+//! on.  Once every document is written, a manifest listing each
+//! shard's document count and SHA-256 checksum is written next to
+//! them as `/data/cases.manifest.json`, so that loaders can process
+//! and verify the shards independently.  The same files can be read
+//! back with `read_documents`, given either a single file or a
+//! manifest.
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde_json::{self, Value};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use ::errors::EBResult;
+use ::types::*;
+
+
+/// When to start a new shard and how to write them
+#[derive(Debug,Clone,PartialEq)]
+pub struct ShardOptions {
+    /// Most documents in a shard
+    pub max_documents: Option<usize>,
+    /// Most bytes of (uncompressed) documents in a shard
+    pub max_bytes: Option<u64>,
+    pub gzip: bool,
+}
+
+
+/// A shard that has been written
+#[derive(Debug,Clone)]
+pub struct ShardInfo {
+    pub path: PathBuf,
+    pub documents: usize,
+    /// Size of the file as written, i.e. compressed if gzipped
+    pub bytes: u64,
+    /// SHA-256 of the file as written
+    pub checksum: String,
+}
+
+
+/// Passes writes through, keeping a checksum and count of the bytes
+struct Checksummed<W> {
+    inner: W,
+    hasher: Sha256,
+    bytes: u64,
+}
+
+
+enum ShardFile {
+    Plain(Checksummed<BufWriter<File>>),
+    Gzip(GzEncoder<Checksummed<BufWriter<File>>>),
+}
+
+
+struct Shard {
+    path: PathBuf,
+    file: ShardFile,
+    documents: usize,
+    bytes: u64,
+}
+
+
+pub struct ShardedWriter {
+    path: PathBuf,
+    options: ShardOptions,
+    current: Option<Shard>,
+    shards: Vec<ShardInfo>,
+}
+
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = try!(self.inner.write(buf));
+        self.hasher.input(&buf[..written]);
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+impl ShardOptions {
+    pub fn new() -> ShardOptions {
+        ShardOptions { max_documents: None, max_bytes: None, gzip: false }
+    }
+
+    /// Whether documents should be written through a ShardedWriter
+    /// rather than to a single plain file
+    pub fn is_enabled(&self) -> bool {
+        self.max_documents.is_some() || self.max_bytes.is_some() || self.gzip
+    }
+}
+
+
+impl ShardInfo {
+    pub fn to_json(&self) -> Doc {
+        let file_name = self.path.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned());
+        doc!({
+            "path"; file_name,
+            "documents"; self.documents,
+            "bytes"; self.bytes,
+            "sha256"; self.checksum
+        })
+    }
+}
+
+
+impl Shard {
+    fn create(path: PathBuf, gzip: bool) -> EBResult<Shard> {
+        let file = Checksummed { inner: BufWriter::new(try!(File::create(&path))), hasher: Sha256::new(), bytes: 0 };
+        let file = match gzip {
+            true => ShardFile::Gzip(GzEncoder::new(file, Compression::Default)),
+            false => ShardFile::Plain(file),
+        };
+        Ok(Shard { path: path, file: file, documents: 0, bytes: 0 })
+    }
+
+    fn write_line(&mut self, line: &str) -> EBResult<()> {
+        match self.file {
+            ShardFile::Plain(ref mut file) => try!(writeln!(file, "{}", line)),
+            ShardFile::Gzip(ref mut file) => try!(writeln!(file, "{}", line)),
+        }
+        self.documents += 1;
+        self.bytes += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn finish(self) -> EBResult<ShardInfo> {
+        let mut file = match self.file {
+            ShardFile::Plain(file) => file,
+            ShardFile::Gzip(file) => try!(file.finish()),
+        };
+        try!(file.flush());
+        info!("Wrote {} documents to {}", self.documents, self.path.display());
+        Ok(ShardInfo {
+            path: self.path,
+            documents: self.documents,
+            bytes: file.bytes,
+            checksum: file.hasher.result_str(),
+        })
+    }
+}
+
+
+impl ShardedWriter {
+    /// Shards are named after `path` with their number before its
+    /// extension
+    pub fn new<P: AsRef<Path>>(path: P, options: ShardOptions) -> ShardedWriter {
+        ShardedWriter { path: path.as_ref().to_path_buf(), options: options, current: None, shards: Vec::new() }
+    }
+
+    /// The path of the shard with the given number
+    fn shard_path(&self, number: usize) -> PathBuf {
+        let stem = self.path.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+        let mut name = match self.path.extension() {
+            Some(extension) => format!("{}.{:05}.{}", stem, number, extension.to_string_lossy()),
+            None => format!("{}.{:05}", stem, number),
+        };
+        if self.options.gzip {
+            name.push_str(".gz");
+        }
+        self.path.with_file_name(name)
+    }
+
+    /// The path the manifest is written to
+    pub fn manifest_path(&self) -> PathBuf {
+        let stem = self.path.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+        self.path.with_file_name(format!("{}.manifest.json", stem))
+    }
+
+    /// Whether adding a document of `bytes` would exceed the limits
+    fn is_full(&self, shard: &Shard, bytes: u64) -> bool {
+        // A document larger than the byte limit still gets a shard
+        shard.documents > 0 && (
+            self.options.max_documents.map_or(false, |max| shard.documents >= max)
+                || self.options.max_bytes.map_or(false, |max| shard.bytes + bytes > max))
+    }
+
+    /// Writes a document as a line, starting a new shard if needed
+    pub fn write_line(&mut self, line: &str) -> EBResult<()> {
+        let full = self.current.as_ref().map_or(true, |shard| self.is_full(shard, line.len() as u64 + 1));
+        if full {
+            if let Some(shard) = self.current.take() {
+                self.shards.push(try!(shard.finish()));
+            }
+            let path = self.shard_path(self.shards.len());
+            self.current = Some(try!(Shard::create(path, self.options.gzip)));
+        }
+        self.current.as_mut().unwrap().write_line(line)
+    }
+
+    /// Finishes the last shard and writes the manifest
+    pub fn finish(mut self) -> EBResult<Vec<ShardInfo>> {
+        if let Some(shard) = self.current.take() {
+            self.shards.push(try!(shard.finish()));
+        }
+
+        let shards = self.shards.iter().map(|shard| shard.to_json()).collect::<Vec<_>>();
+        let documents = self.shards.iter().map(|shard| shard.documents).sum::<usize>();
+        let manifest = doc!({"documents"; documents, "shards"; shards});
+
+        let mut file = BufWriter::new(try!(File::create(self.manifest_path())));
+        try!(writeln!(file, "{}", serde_json::to_string_pretty(&manifest).unwrap()));
+        try!(file.flush());
+        Ok(self.shards)
+    }
+}
+
+
+/// The files a build was written to, with the SHA-256 the manifest
+/// lists for each: the shards listed in a manifest, or the given
+/// file itself
+fn output_files(path: &Path) -> EBResult<Vec<(PathBuf, Option<String>)>> {
+    if !path.to_string_lossy().ends_with(".manifest.json") {
+        return Ok(vec![(path.to_path_buf(), None)])
+    }
+    let mut contents = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut contents));
+    let manifest: Value = try!(serde_json::from_str(&*contents)
+        .map_err(|err| format!("{}: invalid manifest: {}", path.display(), err)));
+    let shards = try!(manifest.find("shards").and_then(|shards| shards.as_array())
+        .ok_or(format!("{}: manifest has no shards", path.display())));
+    shards.iter().map(|shard| {
+        let checksum = shard.find("sha256").and_then(|checksum| checksum.as_str()).map(String::from);
+        match shard.find("path").and_then(|name| name.as_str()) {
+            Some(name) => Ok((path.with_file_name(name), checksum)),
+            None => Err(format!("{}: shard without a path", path.display()).into()),
+        }
+    }).collect()
+}
+
+
+/// The SHA-256 of the file's contents
+fn file_checksum(path: &Path) -> EBResult<String> {
+    let mut file = try!(File::open(path));
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        match try!(file.read(&mut buffer)) {
+            0 => return Ok(hasher.result_str()),
+            read => hasher.input(&buffer[..read]),
+        }
+    }
+}
+
+
+/// The documents of a build, read a line at a time
+pub struct Documents {
+    files: ::std::vec::IntoIter<PathBuf>,
+    current: Option<(PathBuf, io::Lines<Box<BufRead>>, usize)>,
+}
+
+
+impl Documents {
+    fn open(path: &Path) -> EBResult<io::Lines<Box<BufRead>>> {
+        let file = try!(File::open(path));
+        let reader: Box<BufRead> = match path.extension().map_or(false, |extension| extension == "gz") {
+            true => Box::new(BufReader::new(try!(GzDecoder::new(file)))),
+            false => Box::new(BufReader::new(file)),
+        };
+        Ok(reader.lines())
+    }
+}
+
+
+impl Iterator for Documents {
+    type Item = EBResult<Doc>;
+
+    fn next(&mut self) -> Option<EBResult<Doc>> {
+        loop {
+            if let Some((ref path, ref mut lines, ref mut number)) = self.current {
+                match lines.next() {
+                    Some(Err(err)) => return Some(Err(err.into())),
+                    Some(Ok(ref line)) if line.trim().is_empty() => { *number += 1; continue },
+                    Some(Ok(line)) => {
+                        *number += 1;
+                        return Some(serde_json::from_str(&*line).map_err(|err| {
+                            format!("{}:{}: invalid document: {}", path.display(), number, err).into()
+                        }))
+                    },
+                    None => {},
+                }
+            }
+            let path = match self.files.next() {
+                Some(path) => path,
+                None => return None,
+            };
+            match Documents::open(&path) {
+                Ok(lines) => self.current = Some((path, lines, 0)),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+
+/// Reads the documents of a build, one per line, decompressing
+/// gzipped files.  The shards listed in a manifest are checked against
+/// their SHA-256 before any documents are read
+pub fn read_documents<P: AsRef<Path>>(path: P) -> EBResult<Documents> {
+    let files = try!(output_files(path.as_ref()));
+    for &(ref file_path, ref checksum) in &files {
+        if let Some(ref checksum) = *checksum {
+            if try!(file_checksum(file_path)) != *checksum {
+                return Err(format!("{}: SHA-256 doesn't match the manifest", file_path.display()).into())
+            }
+        }
+    }
+    let paths = files.into_iter().map(|(file_path, _)| file_path).collect::<Vec<_>>();
+    Ok(Documents { files: paths.into_iter(), current: None })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crypto::digest::Digest;
+    use crypto::sha2::Sha256;
+    use serde_json::{self, Value};
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+
+    use super::*;
+    use ::selection::random_seed;
+
+    /// A directory of its own for each test, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> TempDir {
+            let path = env::temp_dir().join(format!("esbuild-output-{:x}", random_seed()));
+            fs::create_dir(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn write(path: &PathBuf, options: ShardOptions, count: usize) -> Vec<ShardInfo> {
+        let mut writer = ShardedWriter::new(path, options);
+        for i in 0..count {
+            writer.write_line(&*serde_json::to_string(&doc!({"case_id"; format!("c{}", i)})).unwrap()).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn case_ids(path: PathBuf) -> Vec<String> {
+        read_documents(path).unwrap()
+            .map(|doc| doc.unwrap()["case_id"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn shards_roll_over_at_the_document_limit() {
+        let dir = TempDir::new();
+        let options = ShardOptions { max_documents: Some(2), ..ShardOptions::new() };
+        let shards = write(&dir.0.join("cases.json"), options, 5);
+
+        let names = shards.iter().map(|shard| shard.path.file_name().unwrap().to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["cases.00000.json", "cases.00001.json", "cases.00002.json"]);
+        assert_eq!(shards.iter().map(|shard| shard.documents).collect::<Vec<_>>(), vec![2, 2, 1]);
+        assert_eq!(case_ids(dir.0.join("cases.manifest.json")), vec!["c0", "c1", "c2", "c3", "c4"]);
+    }
+
+    #[test]
+    fn shards_roll_over_at_the_byte_limit() {
+        let dir = TempDir::new();
+        // Each document is 17 bytes with its newline, so two fit
+        let options = ShardOptions { max_bytes: Some(40), gzip: true, ..ShardOptions::new() };
+        let shards = write(&dir.0.join("cases.json"), options, 5);
+
+        assert_eq!(shards.iter().map(|shard| shard.documents).collect::<Vec<_>>(), vec![2, 2, 1]);
+        assert!(shards.iter().all(|shard| shard.path.to_string_lossy().ends_with(".json.gz")));
+        assert_eq!(case_ids(dir.0.join("cases.manifest.json")), vec!["c0", "c1", "c2", "c3", "c4"]);
+
+        // A document larger than the limit still gets a shard
+        let options = ShardOptions { max_bytes: Some(10), ..ShardOptions::new() };
+        assert_eq!(write(&dir.0.join("big.json"), options, 2).len(), 2);
+    }
+
+    #[test]
+    fn manifests_list_each_shards_checksum() {
+        let dir = TempDir::new();
+        let options = ShardOptions { max_documents: Some(3), gzip: true, ..ShardOptions::new() };
+        let shards = write(&dir.0.join("cases.json"), options, 5);
+
+        let mut contents = String::new();
+        File::open(dir.0.join("cases.manifest.json")).unwrap().read_to_string(&mut contents).unwrap();
+        let manifest: Value = serde_json::from_str(&*contents).unwrap();
+        assert_eq!(manifest.find("documents"), Some(&Value::U64(5)));
+
+        for (shard, listed) in shards.iter().zip(manifest.find("shards").unwrap().as_array().unwrap()) {
+            let mut bytes = Vec::new();
+            File::open(&shard.path).unwrap().read_to_end(&mut bytes).unwrap();
+            let mut hasher = Sha256::new();
+            hasher.input(&bytes);
+            assert_eq!(listed.find("sha256").and_then(|sha| sha.as_str()), Some(&*hasher.result_str()));
+            assert_eq!(listed.find("bytes"), Some(&Value::U64(bytes.len() as u64)));
+        }
+    }
+
+    #[test]
+    fn shards_that_dont_match_the_manifest_are_errors() {
+        let dir = TempDir::new();
+        let options = ShardOptions { max_documents: Some(3), ..ShardOptions::new() };
+        let shards = write(&dir.0.join("cases.json"), options, 5);
+
+        let mut shard = OpenOptions::new().append(true).open(&shards[1].path).unwrap();
+        writeln!(shard, "{}", r#"{"case_id": "c5"}"#).unwrap();
+        assert!(read_documents(dir.0.join("cases.manifest.json")).is_err());
+    }
+}
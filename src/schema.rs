@@ -0,0 +1,387 @@
+//! Resolving dictionary schemas and the types of their properties.
+//!
+//! build.rs includes this module to generate `models.rs`, and the
+//! datamodel loads the dictionary with it, so that `$ref`s and
+//! property types are read the same way in both.
+
+use std::collections::BTreeMap;
+use yaml_rust::{Yaml, YamlLoader};
+
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum PropertyType {
+    Integer,
+    Decimal,
+    String,
+    Boolean,
+    /// A string with `format: date-time`
+    Datetime,
+    /// One of the listed values
+    Enum(Vec<String>),
+    /// A list of values of the inner type
+    Array(Box<PropertyType>),
+    Object,
+    /// Either null or the inner type, e.g. `type: [string, "null"]`
+    Nullable(Box<PropertyType>),
+    /// Any of the types, e.g. `type: [string, number]` or `oneOf`
+    Union(Vec<PropertyType>),
+}
+
+
+/// A schema with its `$ref`s inlined
+#[derive(Debug)]
+pub struct SchemaNode {
+    pub key: String,
+    pub value: Option<String>,
+    pub children: Vec<SchemaNode>,
+}
+
+
+/// Inlines the `$ref`s in the schemas, which are keyed by id
+pub struct Resolver {
+    schemas: BTreeMap<String, Yaml>,
+}
+
+
+impl PropertyType {
+    fn parse(type_str: &str) -> Result<PropertyType, String> {
+        Ok(match &*type_str.to_lowercase() {
+            "bool" => PropertyType::Boolean,
+            "boolean" => PropertyType::Boolean,
+            "datetime" => PropertyType::Datetime,
+            "float" => PropertyType::Decimal,
+            "integer" => PropertyType::Decimal,
+            "number" => PropertyType::Decimal,
+            "object" => PropertyType::Object,
+            "string" => PropertyType::String,
+            _ => return Err(format!("Unknown type: {}", type_str)),
+        })
+    }
+}
+
+
+impl SchemaNode {
+    pub fn new<S>(key: S) -> SchemaNode where S: Into<String> {
+        SchemaNode { key: key.into(), value: None, children: Vec::new() }
+    }
+
+    /// Returns the child with the key.  Keys next to a `$ref` come
+    /// after those it inlines, so they take precedence
+    pub fn get<'a>(&'a self, key: &str) -> Option<&'a SchemaNode> {
+        self.children.iter().rev().find(|child| child.key == key)
+    }
+
+    pub fn get_kv(&self, key: &str) -> Option<String> {
+        self.get(key).and_then(|node| node.value.clone())
+    }
+
+    pub fn get_bool(&self, key: &str) -> bool {
+        self.get_kv(key).map_or(false, |value| value == "true")
+    }
+
+    /// Returns the scalar values of a list, e.g. `required` or `enum`
+    pub fn get_list(&self, key: &str) -> Vec<String> {
+        self.get(key)
+            .map(|list| list.children.iter().filter_map(|item| item.value.clone()).collect())
+            .unwrap_or(Vec::new())
+    }
+
+    /// Returns the links, including those in subgroups, each with the
+    /// entry holding its subgroup if any
+    pub fn links(&self) -> Vec<(&SchemaNode, Option<&SchemaNode>)> {
+        let mut links = Vec::new();
+        for entry in self.get("links").map_or(&[][..], |links| &links.children[..]) {
+            match entry.get("subgroup") {
+                Some(subgroup) => links.extend(subgroup.children.iter().map(|link| (link, Some(entry)))),
+                None => links.push((entry, None)),
+            }
+        }
+        links
+    }
+
+    /// Parses a type name in the context of its schema, which gives
+    /// the items of an array and the values of an enum
+    fn named_type(&self, name: &str) -> Result<PropertyType, String> {
+        Ok(match name {
+            "array" => {
+                let items = match self.get("items") {
+                    Some(items) => try!(items.property_type()),
+                    None => PropertyType::String,
+                };
+                PropertyType::Array(Box::new(items))
+            },
+            "enum" => PropertyType::Enum(self.get_list("enum")),
+            "string" if self.get("enum").is_some() => PropertyType::Enum(self.get_list("enum")),
+            "string" if self.get_kv("format").map_or(false, |f| f == "date-time") => PropertyType::Datetime,
+            _ => try!(PropertyType::parse(name)),
+        })
+    }
+
+    /// Collects the types a property can take from its `type` (either
+    /// a name or a list of names) and any `oneOf`/`anyOf` alternatives.
+    /// `null` is not collected, instead the second value is true if it
+    /// was one of the types
+    fn type_alternatives(&self) -> Result<(Vec<PropertyType>, bool), String> {
+        let mut alternatives = Vec::new();
+        let mut nullable = false;
+
+        if let Some(type_node) = self.get("type") {
+            let names = match type_node.value {
+                Some(ref name) => vec![name.clone()],
+                None => type_node.children.iter().filter_map(|name| name.value.clone()).collect(),
+            };
+            for name in names {
+                match &*name {
+                    "null" => nullable = true,
+                    _ => alternatives.push(try!(self.named_type(&*name))),
+                }
+            }
+        } else if self.get("enum").is_some() {
+            alternatives.push(PropertyType::Enum(self.get_list("enum")));
+        }
+
+        for composite in self.children.iter().filter(|c| c.key == "oneOf" || c.key == "anyOf") {
+            for option in &composite.children {
+                let (option_types, option_nullable) = try!(option.type_alternatives());
+                nullable = nullable || option_nullable;
+                if option_types.is_empty() && !option_nullable {
+                    alternatives.push(PropertyType::String);
+                }
+                alternatives.extend(option_types);
+            }
+        }
+
+        // Alternative enums are a single enum of all their values
+        let mut distinct = Vec::new();
+        let mut enum_index = None;
+        for alternative in alternatives {
+            match (alternative, enum_index) {
+                (PropertyType::Enum(values), Some(index)) => {
+                    if let PropertyType::Enum(ref mut existing) = distinct[index] {
+                        existing.extend(values.into_iter().filter(|v| !existing.contains(v)).collect::<Vec<_>>());
+                    }
+                },
+                (PropertyType::Enum(values), None) => {
+                    enum_index = Some(distinct.len());
+                    distinct.push(PropertyType::Enum(values));
+                },
+                (alternative, _) => if !distinct.contains(&alternative) {
+                    distinct.push(alternative)
+                },
+            }
+        }
+        Ok((distinct, nullable))
+    }
+
+    /// Returns the type of a property schema.  Properties without any
+    /// type or enum are strings
+    pub fn property_type(&self) -> Result<PropertyType, String> {
+        let (mut alternatives, nullable) = try!(self.type_alternatives());
+        let prop_type = match alternatives.len() {
+            0 => PropertyType::String,
+            1 => alternatives.remove(0),
+            _ => PropertyType::Union(alternatives),
+        };
+        Ok(match nullable {
+            true => PropertyType::Nullable(Box::new(prop_type)),
+            false => prop_type,
+        })
+    }
+}
+
+
+pub fn load_yaml(source: &str) -> Result<Yaml, String> {
+    let mut docs = try!(YamlLoader::load_from_str(source).map_err(|err| format!("Invalid YAML: {}", err)));
+    match docs.is_empty() {
+        true => Err("Empty YAML document".into()),
+        false => Ok(docs.remove(0)),
+    }
+}
+
+
+pub fn yaml_str<'a>(yaml: &'a Yaml, key: &str) -> Result<&'a str, String> {
+    yaml[key].as_str().ok_or(format!("unable to parse key '{:}' to string in {:?}", key, yaml))
+}
+
+
+impl Resolver {
+    /// Loads the YAML source of each schema
+    pub fn new<S: AsRef<str>>(sources: &[S]) -> Result<Resolver, String> {
+        let mut schemas = BTreeMap::new();
+        for schema in sources {
+            let schema = try!(load_yaml(schema.as_ref()));
+            let label = try!(yaml_str(&schema, "id")).to_string();
+            schemas.insert(label, schema);
+        }
+        Ok(Resolver { schemas: schemas })
+    }
+
+    /// The schemas keyed by id
+    pub fn schemas(&self) -> &BTreeMap<String, Yaml> {
+        &self.schemas
+    }
+
+    /// Resolves a `$ref` made from within the schema `doc_id` to the
+    /// id of the schema it points into and the value it points to.
+    /// Supports refs to other schemas (`file.yaml#/a/b`), refs within
+    /// the same schema (`#/a/b`) and whole schemas (`file.yaml`).
+    fn dereference<'a>(&'a self, doc_id: &str, identifier: &str) -> Result<(String, &'a Yaml), String> {
+        let (root, pointer) = match identifier.find('#') {
+            Some(idx) => (&identifier[..idx], &identifier[idx + 1..]),
+            None => (identifier, ""),
+        };
+
+        let id = match root {
+            "" => doc_id,
+            _ => root.trim_right_matches(".yaml").trim_right_matches(".yml"),
+        };
+        let schema = try!(self.schemas.get(id).ok_or(format!("missing schema {:?} in $ref {}", id, identifier)));
+
+        // Walk the JSON pointer, e.g. `/a/b~1c/0` -> ["a", "b/c", "0"]
+        let mut resolution = schema;
+        for segment in pointer.split('/').skip(1).filter(|segment| !segment.is_empty()) {
+            let segment = segment.replace("~1", "/").replace("~0", "~");
+            resolution = match *resolution {
+                Yaml::Array(ref items) => try!(segment.parse::<usize>().ok()
+                    .and_then(|idx| items.get(idx))
+                    .ok_or(format!("Unable to resolve $ref {}: no index {}", identifier, segment))),
+                _ => &resolution[&*segment],
+            };
+            if resolution.is_badvalue() {
+                return Err(format!("Unable to resolve $ref {}: no key {:?}", identifier, segment))
+            }
+        }
+
+        Ok((id.to_string(), resolution))
+    }
+
+    fn resolve_hash(&self, key: &str, hash: &BTreeMap<Yaml, Yaml>, doc_id: &str, refs: &mut Vec<String>)
+                    -> Result<Vec<SchemaNode>, String> {
+        let mut nodes = Vec::new();
+        for (child_key, child_schema) in hash {
+            let child_key = try!(child_key.as_str().ok_or("unable to parse string"));
+
+            if "$ref" == child_key {
+                let child_value = try!(child_schema.as_str().ok_or("ref not a string"));
+                let (ref_doc_id, deref) = try!(self.dereference(doc_id, child_value));
+
+                // Refs are inlined, so a ref to itself would never terminate
+                let canonical = format!("{}{}", ref_doc_id, &child_value[child_value.find('#').unwrap_or(child_value.len())..]);
+                if refs.contains(&canonical) {
+                    refs.push(canonical);
+                    return Err(format!("Cyclic $ref: {}", refs.join(" -> ")))
+                }
+
+                refs.push(canonical);
+                let children = try!(self.resolve_in(key, deref, &*ref_doc_id, refs)).children;
+                refs.pop();
+
+                for child in children {
+                    nodes.push(child)
+                }
+
+            } else {
+                let child = try!(self.resolve_in(child_key, child_schema, doc_id, refs));
+                nodes.push(child);
+            }
+        }
+        Ok(nodes)
+    }
+
+    /// Resolves the schema with the given id, inlining all `$ref`s
+    pub fn resolve(&self, doc_id: &str, schema: &Yaml) -> Result<SchemaNode, String> {
+        self.resolve_in("root", schema, doc_id, &mut Vec::new())
+    }
+
+    /// Resolves part of the schema `doc_id`.  `refs` holds the chain
+    /// of `$ref`s currently being resolved, to detect cycles
+    fn resolve_in(&self, key: &str, schema: &Yaml, doc_id: &str, refs: &mut Vec<String>)
+                  -> Result<SchemaNode, String> {
+        let mut node = SchemaNode::new(key);
+
+        // If yaml is a hash
+        if let Some(hash) = schema.as_hash() {
+            for child in try!(self.resolve_hash(key, hash, doc_id, refs)) {
+                node.children.push(child)
+            }
+
+        // If yaml is a list
+        } else if let Some(vec) = schema.as_vec() {
+            for child_schema in vec {
+                node.children.push(try!(self.resolve_in(key, child_schema, doc_id, refs)));
+            }
+
+        // Otherwise just save the value
+        } else {
+            node.value = match *schema {
+                Yaml::String(ref value) => Some(value.clone()),
+                Yaml::Real(ref value) => Some(value.clone()),
+                Yaml::Integer(value) => Some(value.to_string()),
+                Yaml::Boolean(value) => Some(value.to_string()),
+                _ => None,
+            }
+        }
+
+        Ok(node)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolver() -> Resolver {
+        Resolver::new(&[
+            r##"
+id: _definitions
+sizes:
+  small: {type: integer}
+  a/b: {type: boolean}
+states: [{enum: [open]}, {enum: [closed]}]
+"##,
+            r##"
+id: thing
+properties:
+  size: {$ref: "_definitions.yaml#/sizes/small"}
+  flag: {$ref: "_definitions.yaml#/sizes/a~1b"}
+  state: {$ref: "_definitions.yaml#/states/1"}
+  copy: {$ref: "#/properties/size"}
+"##,
+            r##"
+id: loop
+a: {$ref: "#/b"}
+b: {$ref: "#/a"}
+"##,
+        ]).unwrap()
+    }
+
+    fn property(schema: &SchemaNode, key: &str) -> PropertyType {
+        schema.get("properties").and_then(|props| props.get(key)).unwrap().property_type().unwrap()
+    }
+
+    #[test]
+    fn pointers_follow_every_segment() {
+        let resolver = resolver();
+        let thing = resolver.resolve("thing", &resolver.schemas()["thing"]).unwrap();
+        assert_eq!(property(&thing, "size"), PropertyType::Decimal);
+        assert_eq!(property(&thing, "flag"), PropertyType::Boolean);
+        assert_eq!(property(&thing, "state"), PropertyType::Enum(vec!["closed".into()]));
+        assert_eq!(property(&thing, "copy"), PropertyType::Decimal);
+    }
+
+    #[test]
+    fn missing_targets_are_errors() {
+        let resolver = resolver();
+        assert!(resolver.dereference("thing", "_definitions.yaml#/sizes/large").is_err());
+        assert!(resolver.dereference("thing", "_definitions.yaml#/states/2").is_err());
+        assert!(resolver.dereference("thing", "missing.yaml#/sizes").is_err());
+    }
+
+    #[test]
+    fn cyclic_refs_are_errors() {
+        let resolver = resolver();
+        let err = resolver.resolve("loop", &resolver.schemas()["loop"]).unwrap_err();
+        assert!(err.starts_with("Cyclic $ref"), "{}", err);
+    }
+}
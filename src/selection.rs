@@ -0,0 +1,162 @@
+//! Selects the cases to build documents for, so that a single case,
+//! a project or a random sample can be rebuilt without a full build.
+
+use std::collections::HashSet;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ::graph::CachedGraph;
+use ::types::*;
+
+
+/// A linear congruential generator, so that samples are reproducible
+/// given the same seed without needing a dependency
+struct Lcg(u64);
+
+
+impl Lcg {
+    /// Returns a number in `0..bound`
+    fn next(&mut self, bound: usize) -> usize {
+        // Knuth's MMIX constants, the high bits are the most random
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((self.0 >> 33) % bound as u64) as usize
+    }
+}
+
+
+/// A seed that differs between runs, from the time and process id
+pub fn random_seed() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let nanos = now.as_secs().wrapping_mul(1_000_000_000).wrapping_add(now.subsec_nanos() as u64);
+    nanos ^ ((process::id() as u64) << 32)
+}
+
+
+impl CaseSelection {
+    /// Selects every case
+    pub fn all() -> CaseSelection {
+        CaseSelection {
+            case_ids: Vec::new(),
+            submitter_ids: Vec::new(),
+            projects: Vec::new(),
+            sample: None,
+            seed: 0,
+        }
+    }
+
+    /// Whether every case is selected
+    pub fn is_all(&self) -> bool {
+        self.case_ids.is_empty() && self.submitter_ids.is_empty() && self.projects.is_empty()
+            && self.sample.is_none()
+    }
+
+    fn matches(&self, case: &Node) -> bool {
+        if self.case_ids.is_empty() && self.submitter_ids.is_empty() && self.projects.is_empty() {
+            return true
+        }
+        let submitter_id = case.props.get("submitter_id").and_then(|id| id.as_str());
+        self.case_ids.contains(&case.id)
+            || submitter_id.map_or(false, |id| self.submitter_ids.iter().any(|s| s == id))
+            || case.project_id().map_or(false, |id| self.projects.iter().any(|p| p == id))
+    }
+
+    /// Returns the selected cases in the graph, sorted by id
+    pub fn select<'a>(&self, graph: &'a CachedGraph) -> Vec<&'a Node> {
+        let mut cases = graph.nodes_labeled("case").into_iter()
+            .filter(|case| self.matches(case))
+            .collect::<Vec<_>>();
+        cases.sort_by(|a, b| a.id.cmp(&b.id));
+
+        // Point out typos rather than silently building nothing
+        let found_ids = cases.iter().map(|case| &case.id).collect::<HashSet<_>>();
+        for id in self.case_ids.iter().filter(|id| !found_ids.contains(id)) {
+            warn!("Case {} not found", id);
+        }
+        let found_submitter_ids = cases.iter()
+            .filter_map(|case| case.props.get("submitter_id").and_then(|id| id.as_str()))
+            .collect::<HashSet<_>>();
+        for id in self.submitter_ids.iter().filter(|id| !found_submitter_ids.contains(&***id)) {
+            warn!("Case with submitter_id {} not found", id);
+        }
+
+        if let Some(size) = self.sample {
+            // Logged so that the sample can be rebuilt with --seed
+            info!("Sampling {} of {} cases with seed {}", size, cases.len(), self.seed);
+
+            // Partial Fisher-Yates shuffle of the first `size` cases
+            let mut lcg = Lcg(self.seed);
+            let size = size.min(cases.len());
+            for i in 0..size {
+                let j = i + lcg.next(cases.len() - i);
+                cases.swap(i, j);
+            }
+            cases.truncate(size);
+            cases.sort_by(|a, b| a.id.cmp(&b.id));
+        }
+
+        info!("Selected {} cases", cases.len());
+        cases
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ::graph::CachedGraph;
+    use ::types::*;
+
+    /// Cases c00 to c19, half in each of two projects
+    fn graph() -> CachedGraph {
+        let mut graph = CachedGraph::new();
+        for i in 0..20 {
+            let project = if i % 2 == 0 { "TCGA-BRCA" } else { "TCGA-LUAD" };
+            let props = doc!({"submitter_id"; format!("S{}", i), "project_id"; project});
+            graph.add_node(Node::new("case".into(), format!("c{:02}", i), props, Doc::new(), Vec::new()));
+        }
+        graph
+    }
+
+    fn ids(cases: Vec<&Node>) -> Vec<String> {
+        cases.into_iter().map(|case| case.id.clone()).collect()
+    }
+
+    fn sample(size: usize, seed: u64) -> CaseSelection {
+        CaseSelection { sample: Some(size), seed: seed, ..CaseSelection::all() }
+    }
+
+    #[test]
+    fn cases_match_any_criterion() {
+        let graph = graph();
+        let selection = CaseSelection {
+            case_ids: vec!["c03".into()],
+            submitter_ids: vec!["S4".into()],
+            ..CaseSelection::all()
+        };
+        assert_eq!(ids(selection.select(&graph)), vec!["c03", "c04"]);
+
+        let selection = CaseSelection { projects: vec!["TCGA-LUAD".into()], ..CaseSelection::all() };
+        assert_eq!(ids(selection.select(&graph)).len(), 10);
+        assert_eq!(ids(CaseSelection::all().select(&graph)).len(), 20);
+    }
+
+    #[test]
+    fn samples_are_reproducible() {
+        let graph = graph();
+        // The same seed always picks the same cases, in order of id
+        let first = ids(sample(5, 42).select(&graph));
+        assert_eq!(first, vec!["c06", "c10", "c13", "c14", "c18"]);
+        assert_eq!(first, ids(sample(5, 42).select(&graph)));
+        assert_ne!(first, ids(sample(5, 43).select(&graph)));
+    }
+
+    #[test]
+    fn samples_are_taken_from_the_matching_cases() {
+        let graph = graph();
+        let selection = CaseSelection { projects: vec!["TCGA-BRCA".into()], ..sample(3, 7) };
+        let cases = selection.select(&graph);
+        assert_eq!(cases.len(), 3);
+        assert!(cases.iter().all(|case| case.project_id() == Some("TCGA-BRCA")));
+
+        assert_eq!(ids(sample(50, 7).select(&graph)).len(), 20);
+    }
+}
@@ -3,6 +3,8 @@ use std::collections::HashMap;
 use serde_json::Map;
 use regex::Regex;
 
+pub use ::schema::{PropertyType, SchemaNode};
+
 pub type Doc = Map<String, Value>;
 
 #[derive(Debug)]
@@ -15,7 +17,7 @@ pub struct Node {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug,Clone,PartialEq)]
 pub enum NodeCategory {
     DataFile,
     Biospecimen,
@@ -28,47 +30,47 @@ pub enum NodeCategory {
     Other,
 }
 
-#[derive(Debug)]
-pub enum PropertyType {
-    Integer,
-    Decimal,
-    String,
-    Boolean,
-}
-
-#[derive(Debug)]
-pub struct SchemaNode {
-    pub key: String,
-    pub value: Option<String>,
-    pub children: Vec<SchemaNode>,
-}
-
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub struct NodeType {
     pub label: String,
     pub props: HashMap<String, PropertyType>,
     pub category: NodeCategory,
     pub links: Vec<EdgeType>,
     pub backrefs: Vec<EdgeType>,
+    pub required: Vec<String>,
+    pub unique_keys: Vec<Vec<String>>,
+    pub system_properties: Vec<String>,
 }
 
-#[derive(Debug,Clone)]
+#[derive(Debug,Clone,PartialEq)]
 pub struct Edge {
     pub src_id: String,
     pub dst_id: String,
     pub label: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone,PartialEq)]
+pub enum Multiplicity {
+    OneToOne,
+    OneToMany,
+    ManyToOne,
+    ManyToMany,
+}
+
+#[derive(Debug,Clone)]
 pub struct EdgeType {
     pub name: String,
     pub label: String,
     pub backref: String,
     pub src_label: String,
     pub dst_label: String,
+    pub multiplicity: Multiplicity,
+    pub required: bool,
+    pub subgroup_exclusive: bool,
+    pub subgroup_required: bool,
 }
 
-#[derive(Debug,Clone)]
+#[derive(Debug,Clone,PartialEq)]
 pub enum Correlation {
     ToOne,
     ToMany,
@@ -84,10 +86,22 @@ pub struct CachingOptions {
     pub omitted_projects: Vec<String>,
     pub index_file_extensions: Vec<String>,
     pub possible_associated_entites: Vec<String>,
-    pub supplement_regexes: Vec<Regex>,
+    pub supplement_regexes: Vec<(String, Regex)>,
 }
 
-#[derive(Debug)]
+/// Which cases to build documents for.  Cases matching any of the
+/// ids, submitter ids or projects are selected, or every case if none
+/// are given, and then `sample` of them are picked at random
+#[derive(Debug,Clone)]
+pub struct CaseSelection {
+    pub case_ids: Vec<String>,
+    pub submitter_ids: Vec<String>,
+    pub projects: Vec<String>,
+    pub sample: Option<usize>,
+    pub seed: u64,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
 pub enum IndexType {
     Active,
     Legacy,
@@ -101,13 +115,19 @@ pub struct Options {
     pub index_file_extensions: Vec<String>,
     pub possible_associated_entites: Vec<String>,
     pub index_type: IndexType,
+    /// Sort lists of child documents by id so that builds from the
+    /// same data are identical.  Object keys need no sorting, Doc is
+    /// ordered already
+    pub deterministic: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub struct TypeTree {
     pub label: String,
     pub title: String,
     pub correlation: Correlation,
+    /// Set in the configuration, used instead of the dictionary's
+    pub correlation_override: Option<Correlation>,
     pub children: Vec<TypeTree>,
 }
 
@@ -122,4 +142,7 @@ pub struct NodeTree<'a> {
 #[derive(Debug)]
 pub struct Datamodel {
     pub node_types: HashMap<String, NodeType>,
+    /// Node types with project specific overrides applied, keyed by
+    /// project_id and then label
+    pub project_node_types: HashMap<String, HashMap<String, NodeType>>,
 }
@@ -0,0 +1,276 @@
+//! Validates the cached graph against the dictionary.
+//!
+//! Every node's properties are checked against the NodeType of its
+//! label.  Problems are collected per label and property so that a
+//! single bad loader run shows up as one line with a few sample node
+//! ids rather than one line per node.
+
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use ::graph::CachedGraph;
+use ::types::*;
+
+/// How many node ids to keep as examples of each problem
+const SAMPLE_SIZE: usize = 5;
+
+
+#[derive(Debug,Clone,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Violation {
+    /// The node's label isn't in the dictionary
+    UnknownLabel,
+    /// The property isn't defined for the node's label
+    UnknownProperty,
+    /// The value doesn't match the (expected) property type
+    WrongType(String),
+    /// The value isn't one of the enumerated values
+    NotInEnum,
+    /// A required property is missing or null
+    MissingRequired,
+}
+
+
+#[derive(Debug)]
+pub struct Problem {
+    pub count: usize,
+    pub sample_ids: Vec<String>,
+    pub sample_values: Vec<Value>,
+}
+
+
+#[derive(Debug)]
+pub struct ValidationReport {
+    pub nodes_checked: usize,
+    /// Problems keyed by label, then property, then violation
+    pub problems: BTreeMap<String, BTreeMap<String, BTreeMap<Violation, Problem>>>,
+}
+
+
+/// Loosely checks for an ISO 8601 date, e.g. `2016-05-02T14:37:43.601123-05:00`
+fn is_datetime(value: &str) -> bool {
+    value.len() >= 10 && value.chars().take(10).enumerate().all(|(i, c)| match i {
+        4 | 7 => c == '-',
+        _ => c.is_digit(10),
+    })
+}
+
+
+impl PropertyType {
+    /// Returns whether a (non null) value is of this type
+    pub fn accepts(&self, value: &Value) -> bool {
+        match (self, value) {
+            (&PropertyType::Integer, &Value::I64(_)) => true,
+            (&PropertyType::Integer, &Value::U64(_)) => true,
+            (&PropertyType::Decimal, &Value::I64(_)) => true,
+            (&PropertyType::Decimal, &Value::U64(_)) => true,
+            (&PropertyType::Decimal, &Value::F64(_)) => true,
+            (&PropertyType::String, &Value::String(_)) => true,
+            (&PropertyType::Boolean, &Value::Bool(_)) => true,
+            (&PropertyType::Datetime, &Value::String(ref value)) => is_datetime(value),
+            (&PropertyType::Enum(ref values), &Value::String(ref value)) => values.contains(value),
+            (&PropertyType::Array(ref inner), &Value::Array(ref items)) => items.iter().all(|item| inner.accepts(item)),
+            (&PropertyType::Object, &Value::Object(_)) => true,
+            (&PropertyType::Nullable(_), &Value::Null) => true,
+            (&PropertyType::Nullable(ref inner), value) => inner.accepts(value),
+            (&PropertyType::Union(ref types), value) => types.iter().any(|t| t.accepts(value)),
+            _ => false,
+        }
+    }
+}
+
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            Violation::UnknownLabel => "unknown label",
+            Violation::UnknownProperty => "unknown property",
+            Violation::WrongType(ref expected) => return write!(f, "wrong type, expected {}", expected),
+            Violation::NotInEnum => "value not in enum",
+            Violation::MissingRequired => "missing required property",
+        })
+    }
+}
+
+
+impl ValidationReport {
+    pub fn new() -> ValidationReport {
+        ValidationReport { nodes_checked: 0, problems: BTreeMap::new() }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// The total number of problems found across all nodes
+    pub fn problem_count(&self) -> usize {
+        self.problems.values()
+            .flat_map(|props| props.values())
+            .flat_map(|violations| violations.values())
+            .map(|problem| problem.count)
+            .sum()
+    }
+
+    fn add(&mut self, node: &Node, key: &str, violation: Violation, value: &Value) {
+        let problem = self.problems
+            .entry(node.label.clone()).or_insert_with(BTreeMap::new)
+            .entry(key.to_string()).or_insert_with(BTreeMap::new)
+            .entry(violation).or_insert(Problem { count: 0, sample_ids: Vec::new(), sample_values: Vec::new() });
+
+        problem.count += 1;
+        if problem.sample_ids.len() < SAMPLE_SIZE {
+            problem.sample_ids.push(node.id.clone());
+            problem.sample_values.push(value.clone());
+        }
+    }
+
+    /// Checks a single node against its NodeType
+    pub fn check_node(&mut self, datamodel: &Datamodel, node: &Node) {
+        self.nodes_checked += 1;
+
+        let node_type = match datamodel.node_type_for_project(&node.label, node.project_id()) {
+            Some(node_type) => node_type,
+            None => return self.add(node, "*", Violation::UnknownLabel, &Value::Null),
+        };
+
+        for (key, value) in &node.props {
+            let prop_type = match node_type.props.get(key) {
+                Some(prop_type) => prop_type,
+                None => {
+                    self.add(node, key, Violation::UnknownProperty, value);
+                    continue
+                },
+            };
+
+            if value.is_null() {
+                continue
+            }
+
+            if !prop_type.accepts(value) {
+                // Strings of an enum type are valid apart from their value
+                let violation = match (value, prop_type.enum_values()) {
+                    (&Value::String(_), Some(_)) => Violation::NotInEnum,
+                    _ => Violation::WrongType(format!("{:?}", prop_type)),
+                };
+                self.add(node, key, violation, value);
+            }
+        }
+
+        // Required links are also listed as required, only check properties
+        for key in node_type.required.iter().filter(|key| node_type.props.contains_key(*key)) {
+            if node.props.get(key).map_or(true, |value| value.is_null()) {
+                self.add(node, key, Violation::MissingRequired, &Value::Null);
+            }
+        }
+    }
+}
+
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "Checked {} nodes, found {} problems", self.nodes_checked, self.problem_count()));
+        for (label, props) in &self.problems {
+            for (key, violations) in props {
+                for (violation, problem) in violations {
+                    try!(write!(f, "{}.{}: {} ({} nodes, e.g. {}", label, key, violation,
+                                problem.count, problem.sample_ids.join(", ")));
+                    match *violation {
+                        Violation::UnknownLabel | Violation::MissingRequired => {},
+                        _ => {
+                            let values = problem.sample_values.iter()
+                                .map(|value| value.to_string())
+                                .collect::<Vec<_>>();
+                            try!(write!(f, " with {}", values.join(", ")));
+                        },
+                    }
+                    try!(writeln!(f, ")"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Checks every node in the graph against the dictionary
+pub fn validate(datamodel: &Datamodel, graph: &CachedGraph) -> ValidationReport {
+    let mut report = ValidationReport::new();
+    let mut nodes = graph.nodes.values().collect::<Vec<_>>();
+    nodes.sort_by(|a, b| a.id.cmp(&b.id));
+    for node in nodes {
+        report.check_node(datamodel, node);
+    }
+    report
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use super::*;
+
+    fn sample(props: Doc) -> Node {
+        Node::new("sample".into(), "sample-1".into(), props, Doc::new(), Vec::new())
+    }
+
+    fn violations(report: &ValidationReport, key: &str) -> Vec<Violation> {
+        report.problems.get("sample").and_then(|props| props.get(key))
+            .map_or(Vec::new(), |violations| violations.keys().cloned().collect())
+    }
+
+    #[test]
+    fn types_accept_their_values() {
+        assert!(PropertyType::Decimal.accepts(&Value::U64(3)));
+        assert!(PropertyType::Decimal.accepts(&Value::F64(0.5)));
+        assert!(!PropertyType::Integer.accepts(&Value::F64(0.5)));
+        assert!(PropertyType::Datetime.accepts(&Value::String("2016-05-02T14:37:43.601123-05:00".into())));
+        assert!(!PropertyType::Datetime.accepts(&Value::String("yesterday".into())));
+        assert!(PropertyType::Enum(vec!["Tumor".into()]).accepts(&Value::String("Tumor".into())));
+        assert!(!PropertyType::Enum(vec!["Tumor".into()]).accepts(&Value::String("Normal".into())));
+
+        let nullable = PropertyType::Nullable(Box::new(PropertyType::String));
+        assert!(nullable.accepts(&Value::Null));
+        assert!(nullable.accepts(&Value::String("x".into())));
+        assert!(!PropertyType::String.accepts(&Value::Null));
+
+        let array = PropertyType::Array(Box::new(PropertyType::Boolean));
+        assert!(array.accepts(&Value::Array(vec![Value::Bool(true), Value::Bool(false)])));
+        assert!(!array.accepts(&Value::Array(vec![Value::Bool(true), Value::String("x".into())])));
+
+        let union = PropertyType::Union(vec![PropertyType::String, PropertyType::Boolean]);
+        assert!(union.accepts(&Value::Bool(true)));
+        assert!(!union.accepts(&Value::U64(1)));
+    }
+
+    #[test]
+    fn nodes_are_checked_against_their_type() {
+        let datamodel = Datamodel::new().unwrap();
+        let mut report = ValidationReport::new();
+        report.check_node(&datamodel, &sample(doc!({
+            "submitter_id"; "S1",
+            "sample_type_id"; "01",
+            "tissue_type"; "Bogus",
+            "is_ffpe"; "yes",
+            "initial_weight"; Value::Null,
+            "colour"; "red"
+        })));
+
+        assert_eq!(violations(&report, "tissue_type"), vec![Violation::NotInEnum]);
+        assert_eq!(violations(&report, "is_ffpe"), vec![Violation::WrongType("Boolean".into())]);
+        assert_eq!(violations(&report, "colour"), vec![Violation::UnknownProperty]);
+        assert_eq!(violations(&report, "sample_type"), vec![Violation::MissingRequired]);
+        assert_eq!(violations(&report, "initial_weight"), vec![]);
+        assert_eq!(violations(&report, "submitter_id"), vec![]);
+        assert_eq!(report.problem_count(), 4);
+    }
+
+    #[test]
+    fn unknown_labels_are_reported_once() {
+        let datamodel = Datamodel::new().unwrap();
+        let mut report = ValidationReport::new();
+        let node = Node::new("widget".into(), "widget-1".into(), doc!({"size"; 3}), Doc::new(), Vec::new());
+        report.check_node(&datamodel, &node);
+        assert_eq!(report.problems["widget"]["*"].keys().collect::<Vec<_>>(), vec![&Violation::UnknownLabel]);
+        assert_eq!(report.problem_count(), 1);
+    }
+}